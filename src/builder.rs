@@ -0,0 +1,412 @@
+use std::{sync::Arc, time::Duration};
+
+use fred::pool::RedisPool;
+
+use crate::{
+    cache::SessionCache, Compression, Error, JsonCodec, Keyring, MergeStrategy, RedisSessionStore,
+    SessionCodec, SessionLimitPolicy, StorageMode, TimeoutPolicy, TombstoneAudit,
+    UnchangedSessions, INDEX_KEY,
+};
+
+/// configures and creates a [`RedisSessionStore`]
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Duration;
+/// use async_fred_session::RedisSessionStore;
+/// use fred::{pool::RedisPool, prelude::*};
+///
+/// let conf = RedisConfig::from_url("redis://127.0.0.1:6379").unwrap();
+/// let pool = RedisPool::new(conf, None, None, 6).unwrap();
+/// pool.connect();
+/// pool.wait_for_connect().await.unwrap();
+///
+/// let store = RedisSessionStore::builder(pool)
+///     .prefix("async-fred-session/")
+///     .default_ttl(Duration::from_secs(60 * 60))
+///     .max_ttl(Duration::from_secs(60 * 60 * 24 * 7))
+///     .build()
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedisSessionStoreBuilder {
+    pool: RedisPool,
+    prefix: Option<String>,
+    default_ttl: Option<Duration>,
+    max_ttl: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    timeout_policy: Option<TimeoutPolicy>,
+    scan_count: Option<u32>,
+    clear_batch_size: usize,
+    clock_skew_tolerance: Duration,
+    codec: Option<Arc<dyn SessionCodec>>,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    keyring: Option<Keyring>,
+    allow_unencrypted: bool,
+    storage: StorageMode,
+    unchanged_sessions: UnchangedSessions,
+    session_index: bool,
+    session_limit: Option<(u32, SessionLimitPolicy)>,
+    versioned_sessions: bool,
+    merge_strategy: MergeStrategy,
+    tombstone_ttl: Option<Duration>,
+    tombstone_audit: Option<TombstoneAudit>,
+    keyspace_notifications: bool,
+    cache: Option<(usize, Duration)>,
+}
+
+impl RedisSessionStoreBuilder {
+    /// creates a builder for a store backed by an existing [`fred::pool::RedisPool`]
+    pub fn new(pool: RedisPool) -> Self {
+        Self {
+            pool,
+            prefix: None,
+            default_ttl: None,
+            max_ttl: None,
+            idle_timeout: None,
+            timeout_policy: None,
+            scan_count: None,
+            clear_batch_size: 1000,
+            clock_skew_tolerance: Duration::ZERO,
+            codec: None,
+            compression: None,
+            compression_threshold: 1024,
+            keyring: None,
+            allow_unencrypted: false,
+            storage: StorageMode::String,
+            unchanged_sessions: UnchangedSessions::Write,
+            session_index: false,
+            session_limit: None,
+            versioned_sessions: false,
+            merge_strategy: MergeStrategy::Fail,
+            tombstone_ttl: None,
+            tombstone_audit: None,
+            keyspace_notifications: false,
+            cache: None,
+        }
+    }
+
+    /// sets the prefix prepended to every key written by the store.
+    ///
+    /// in a redis cluster the prefix has to contain a hash tag, like
+    /// `{sessions}:`, so that every key of the store shares a slot. the
+    /// scripts that enforce the [session limit](Self::session_limit), list
+    /// and destroy the sessions of a user and leave tombstones build the keys
+    /// of other sessions from the prefix instead of declaring them, which
+    /// only works when all of them live on the same node
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// sets the ttl used for sessions that do not have an expiry.
+    /// without it such sessions are kept in redis indefinitely
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// caps the ttl of every session written to redis, regardless of
+    /// the session expiry
+    pub fn max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = Some(ttl);
+        self
+    }
+
+    /// extends the ttl of a session to `idle` every time it is loaded, so
+    /// sessions expire after going unused for that long. loading and
+    /// refreshing the ttl happen in a single `GETEX`, or in a lua script
+    /// with a [session index](Self::session_index) and in hash storage mode.
+    ///
+    /// sessions without an expiry are stored with `idle` as their ttl,
+    /// sessions with an expiry are still rejected once it has passed
+    pub fn sliding_expiration(mut self, idle: Duration) -> Self {
+        self.idle_timeout = Some(idle);
+        self
+    }
+
+    /// enforces idle and absolute timeouts on every session, see
+    /// [`TimeoutPolicy`]
+    pub fn timeout_policy(mut self, policy: TimeoutPolicy) -> Self {
+        self.timeout_policy = Some(policy);
+        self
+    }
+
+    /// sets the `COUNT` hint used for each `SCAN` page when the store
+    /// has to walk its keys
+    pub fn scan_count(mut self, count: u32) -> Self {
+        self.scan_count = Some(count);
+        self
+    }
+
+    /// sets how many keys [`RedisSessionStore::clear`] deletes with a single
+    /// `UNLINK`, 1000 by default
+    pub fn clear_batch_size(mut self, batch_size: usize) -> Self {
+        self.clear_batch_size = batch_size;
+        self
+    }
+
+    /// sets how long past its expiry a loaded session is still accepted,
+    /// to allow for clock differences between the application servers
+    pub fn clock_skew_tolerance(mut self, tolerance: Duration) -> Self {
+        self.clock_skew_tolerance = tolerance;
+        self
+    }
+
+    /// sets the codec sessions are serialized with, [`JsonCodec`] by default
+    pub fn codec(mut self, codec: impl SessionCodec) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// compresses stored sessions that are larger than the
+    /// [compression threshold](Self::compression_threshold)
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// sets the encoded size in bytes above which sessions are compressed,
+    /// 1024 by default
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// encrypts stored sessions with the newest key in the keyring.
+    /// sessions that were stored unencrypted fail to load unless
+    /// [`allow_unencrypted`](Self::allow_unencrypted) is set
+    pub fn encryption(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// keeps loading sessions that were stored unencrypted, while migrating
+    /// a store to [encryption](Self::encryption). anyone who can write to
+    /// redis can then forge sessions, so it should be turned off once the
+    /// unencrypted sessions have been stored again or expired
+    pub fn allow_unencrypted(mut self, allowed: bool) -> Self {
+        self.allow_unencrypted = allowed;
+        self
+    }
+
+    /// sets how sessions are laid out in redis, [`StorageMode::String`] by default
+    pub fn storage_mode(mut self, storage: StorageMode) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// sets what storing a session that has not changed since it was loaded
+    /// does, [`UnchangedSessions::Write`] by default
+    pub fn unchanged_sessions(mut self, unchanged_sessions: UnchangedSessions) -> Self {
+        self.unchanged_sessions = unchanged_sessions;
+        self
+    }
+
+    /// keeps the ids of all sessions in a sorted set scored by their expiry,
+    /// so that counting and listing sessions reads the index instead of
+    /// scanning the keyspace. sessions stored before the index was enabled
+    /// are not in it.
+    ///
+    /// stores without a prefix always keep the index, as it is the only way
+    /// to tell their sessions apart from the other keys in the database
+    ///
+    /// the index lives next to the sessions under the `__afs:index` key, see
+    /// [`prefix`](Self::prefix) for running in a cluster
+    pub fn session_index(mut self, enabled: bool) -> Self {
+        self.session_index = enabled;
+        self
+    }
+
+    /// limits the number of sessions a user can have at the same time.
+    /// storing a new session [bound](RedisSessionStore::bind_user) to a user
+    /// that already has `max` sessions rejects it or evicts another session
+    /// of the user, depending on the policy. the check and the eviction run
+    /// in the same script as the write, so racing logins cannot go over the
+    /// limit
+    pub fn session_limit(mut self, max: u32, policy: SessionLimitPolicy) -> Self {
+        self.session_limit = Some((max, policy));
+        self
+    }
+
+    /// stores a version counter with every session and fails storing a
+    /// session with [`Error::VersionConflict`] if it was stored by someone
+    /// else since it was loaded, instead of overwriting their changes. use
+    /// [`RedisSessionStore::update_session`] to retry the change.
+    ///
+    /// the version is kept in the session data under the reserved
+    /// `__afs:version` key, and in front of the stored value in
+    /// [`StorageMode::String`]
+    pub fn versioned_sessions(mut self, enabled: bool) -> Self {
+        self.versioned_sessions = enabled;
+        self
+    }
+
+    /// sets how conflicts of [versioned sessions](Self::versioned_sessions)
+    /// are resolved, [`MergeStrategy::Fail`] by default
+    pub fn merge_strategy(mut self, strategy: MergeStrategy) -> Self {
+        self.merge_strategy = strategy;
+        self
+    }
+
+    /// leaves a tombstone for `ttl` when a session is destroyed or evicted by
+    /// the [session limit](Self::session_limit). until it expires, storing a
+    /// session with the same id fails with [`Error::SessionDestroyed`], so a
+    /// stale request can not bring the session back
+    pub fn tombstones(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = Some(ttl);
+        self
+    }
+
+    /// calls `audit` with the id of every session that is loaded while it has
+    /// a [tombstone](Self::tombstones), for example to log replayed cookies
+    pub fn tombstone_audit(mut self, audit: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.tombstone_audit = Some(TombstoneAudit(Arc::new(audit)));
+        self
+    }
+
+    /// lets [`RedisSessionStore::session_events`] add the flags it needs to
+    /// the `notify-keyspace-events` setting of the server, which is left as
+    /// is by default
+    pub fn keyspace_notifications(mut self, enabled: bool) -> Self {
+        self.keyspace_notifications = enabled;
+        self
+    }
+
+    /// keeps up to `capacity` loaded sessions in memory for up to `ttl`, so
+    /// loading them again skips redis. stores sharing the prefix drop the
+    /// sessions changed through any of them from their caches, through the
+    /// `__afs:invalidate` pub/sub channel under the prefix, including the
+    /// sessions evicted by the [session limit](Self::session_limit). sessions
+    /// deleted by redis itself, for example when they expire, and sessions
+    /// whose invalidation could not be published stay cached until `ttl`
+    /// runs out, so it should be short.
+    ///
+    /// cached sessions do not slide their expiry, so the cache can not be
+    /// combined with [sliding expiration](Self::sliding_expiration) or idle
+    /// timeouts
+    pub fn cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.cache = Some((capacity, ttl));
+        self
+    }
+
+    /// validates the options and creates the store
+    pub fn build(self) -> Result<RedisSessionStore, Error> {
+        if matches!(&self.prefix, Some(prefix) if prefix.is_empty()) {
+            return Err(invalid("prefix must not be empty"));
+        }
+        if matches!(self.default_ttl, Some(ttl) if ttl.as_millis() == 0) {
+            return Err(invalid("default ttl must be at least one millisecond"));
+        }
+        if matches!(self.max_ttl, Some(ttl) if ttl.as_millis() == 0) {
+            return Err(invalid("max ttl must be at least one millisecond"));
+        }
+        if let (Some(default_ttl), Some(max_ttl)) = (self.default_ttl, self.max_ttl) {
+            if default_ttl > max_ttl {
+                return Err(invalid("default ttl must not be greater than max ttl"));
+            }
+        }
+        if matches!(self.idle_timeout, Some(idle) if idle.as_millis() == 0) {
+            return Err(invalid("idle timeout must be at least one millisecond"));
+        }
+        if let (Some(idle_timeout), Some(max_ttl)) = (self.idle_timeout, self.max_ttl) {
+            if idle_timeout > max_ttl {
+                return Err(invalid("idle timeout must not be greater than max ttl"));
+            }
+        }
+        if matches!(&self.timeout_policy, Some(policy) if !policy.is_valid()) {
+            return Err(invalid("timeouts must be at least one millisecond"));
+        }
+        if self.scan_count == Some(0) {
+            return Err(invalid("scan count must be greater than zero"));
+        }
+        if matches!(self.session_limit, Some((0, _))) {
+            return Err(invalid("session limit must be greater than zero"));
+        }
+        if !self.versioned_sessions && !matches!(self.merge_strategy, MergeStrategy::Fail) {
+            return Err(invalid("merge strategies require versioned sessions"));
+        }
+        if matches!(self.tombstone_ttl, Some(ttl) if ttl.as_millis() == 0) {
+            return Err(invalid("tombstone ttl must be at least one millisecond"));
+        }
+        if self.tombstone_audit.is_some() && self.tombstone_ttl.is_none() {
+            return Err(invalid("tombstone audits require tombstones"));
+        }
+        if let Some((capacity, ttl)) = self.cache {
+            if capacity == 0 || ttl.as_millis() == 0 {
+                return Err(invalid("cache capacity and ttl must be greater than zero"));
+            }
+            if self.idle_timeout.is_some()
+                || self.timeout_policy.is_some_and(|policy| policy.has_idle())
+            {
+                return Err(invalid(
+                    "the cache can not be combined with sliding expiration or idle timeouts",
+                ));
+            }
+        }
+        if self.clear_batch_size == 0 {
+            return Err(invalid("clear batch size must be greater than zero"));
+        }
+        if matches!(&self.compression, Some(compression) if !compression.is_valid_level()) {
+            return Err(invalid("compression level is out of range"));
+        }
+        if matches!(&self.keyring, Some(keyring) if keyring.is_empty()) {
+            return Err(invalid("keyring must contain at least one key"));
+        }
+        if self.allow_unencrypted && self.keyring.is_none() {
+            return Err(invalid("allowing unencrypted sessions requires encryption"));
+        }
+        if self.storage == StorageMode::Hash
+            && (self.codec.is_some() || self.compression.is_some() || self.keyring.is_some())
+        {
+            return Err(invalid(
+                "codecs, compression and encryption are not supported in hash storage mode",
+            ));
+        }
+
+        Ok(self.into_store())
+    }
+
+    /// creates the store without validating the options
+    pub(crate) fn into_store(self) -> RedisSessionStore {
+        let session_index = self.session_index || self.prefix.is_none();
+        let mut store = RedisSessionStore {
+            pool: self.pool,
+            prefix: self.prefix,
+            default_ttl: self.default_ttl,
+            max_ttl: self.max_ttl,
+            idle_timeout: self.idle_timeout,
+            timeout_policy: self.timeout_policy,
+            scan_count: self.scan_count,
+            clear_batch_size: self.clear_batch_size,
+            clock_skew_tolerance: self.clock_skew_tolerance,
+            codec: self.codec.unwrap_or_else(|| Arc::new(JsonCodec)),
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            keyring: self.keyring.map(Arc::new),
+            allow_unencrypted: self.allow_unencrypted,
+            storage: self.storage,
+            unchanged_sessions: self.unchanged_sessions,
+            index_key: None,
+            session_limit: self.session_limit,
+            versioned_sessions: self.versioned_sessions,
+            merge_strategy: self.merge_strategy,
+            tombstone_ttl: self.tombstone_ttl,
+            tombstone_audit: self.tombstone_audit,
+            keyspace_notifications: self.keyspace_notifications,
+            cache: self
+                .cache
+                .map(|(capacity, ttl)| Arc::new(SessionCache::new(capacity, ttl))),
+        };
+        if session_index {
+            store.index_key = Some(store.prefix_key(INDEX_KEY));
+        }
+        store
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidConfig(reason.to_string())
+}
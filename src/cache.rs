@@ -0,0 +1,252 @@
+//! an in-process cache of loaded sessions, kept in sync across nodes through
+//! a redis pub/sub invalidation channel
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_session::{Result, Session};
+use fred::interfaces::{ClientLike, PubsubInterface};
+use tokio::sync::{broadcast::error::RecvError, OnceCell};
+
+use crate::{deep_copy, events::Subscription, RedisSessionStore};
+
+/// the invalidation channel, appended to the prefix. messages are the id of
+/// the session that changed, or empty if every session may have changed
+const INVALIDATION_CHANNEL: &str = "__afs:invalidate";
+
+/// a bounded lru cache of sessions that expire from the cache after a ttl
+pub(crate) struct SessionCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    /// bumped by every invalidation, so sessions loaded before one are not
+    /// cached after it
+    generation: AtomicU64,
+    subscription: OnceCell<Subscription>,
+}
+
+#[derive(Default)]
+struct Entries {
+    sessions: HashMap<String, Entry>,
+    /// the ids of the cached sessions by when they were last used
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct Entry {
+    session: Session,
+    cached_at: Instant,
+    used: u64,
+}
+
+impl SessionCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+            generation: AtomicU64::new(0),
+            subscription: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Session>> {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            sessions,
+            recency,
+            tick,
+        } = &mut *entries;
+
+        let Some(entry) = sessions.get_mut(id) else {
+            return Ok(None);
+        };
+        if entry.cached_at.elapsed() >= self.ttl {
+            recency.remove(&entry.used);
+            sessions.remove(id);
+            return Ok(None);
+        }
+
+        *tick += 1;
+        recency.remove(&entry.used);
+        recency.insert(*tick, id.to_string());
+        entry.used = *tick;
+        Ok(Some(deep_copy(&entry.session)?))
+    }
+
+    fn insert(&self, session: &Session, generation: u64) -> Result {
+        let session = deep_copy(session)?;
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+        let Entries {
+            sessions,
+            recency,
+            tick,
+        } = &mut *entries;
+
+        *tick += 1;
+        let id = session.id().to_string();
+        let entry = Entry {
+            session,
+            cached_at: Instant::now(),
+            used: *tick,
+        };
+        if let Some(replaced) = sessions.insert(id.clone(), entry) {
+            recency.remove(&replaced.used);
+        } else if sessions.len() > self.capacity {
+            if let Some((_, evicted)) = recency.pop_first() {
+                sessions.remove(&evicted);
+            }
+        }
+        recency.insert(*tick, id);
+        Ok(())
+    }
+
+    /// drops the session with the given id, or every session for an empty id
+    fn invalidate(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if id.is_empty() {
+            *entries = Entries::default();
+        } else if let Some(entry) = entries.sessions.remove(id) {
+            entries.recency.remove(&entry.used);
+        }
+    }
+}
+
+impl RedisSessionStore {
+    /// the cached copy of the session with the given id, and the cache
+    /// generation to pass to [`cache_session`](Self::cache_session) if it
+    /// has to be loaded from redis
+    pub(crate) async fn cached_session(&self, id: &str) -> Result<(Option<Session>, u64)> {
+        let Some(cache) = &self.cache else {
+            return Ok((None, 0));
+        };
+        cache
+            .subscription
+            .get_or_try_init(|| self.subscribe_invalidations(cache))
+            .await?;
+        Ok((cache.get(id)?, cache.generation.load(Ordering::SeqCst)))
+    }
+
+    /// caches a session loaded from redis, unless it was invalidated since
+    /// the load started
+    pub(crate) fn cache_session(&self, session: &Session, generation: u64) -> Result {
+        match &self.cache {
+            Some(cache) => cache.insert(session, generation),
+            None => Ok(()),
+        }
+    }
+
+    /// drops the session with the given id, or every session for an empty
+    /// id, from the caches of every store sharing the prefix. this runs after
+    /// the change it announces was written, so failing to publish it is
+    /// ignored rather than failing the change, and the other stores keep the
+    /// session until their cache `ttl` runs out
+    pub(crate) async fn invalidate_cached(&self, id: &str) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        cache.invalidate(id);
+        let channel = self.prefix_key(INVALIDATION_CHANNEL);
+        let _ = self.pool.publish::<(), _, _>(channel, id).await;
+    }
+
+    /// listens to the invalidation channel, dropping every cached session
+    /// after missing messages or reconnecting
+    async fn subscribe_invalidations(&self, cache: &Arc<SessionCache>) -> Result<Subscription> {
+        let mut subscription = self.subscription().await?;
+        let mut messages = subscription.client.on_message();
+        let mut reconnects = subscription.client.on_reconnect();
+        subscription
+            .client
+            .subscribe::<(), _>(self.prefix_key(INVALIDATION_CHANNEL))
+            .await?;
+
+        let weak = Arc::downgrade(cache);
+        subscription.spawn(async move {
+            loop {
+                let id = match messages.recv().await {
+                    Ok(message) => message.value.as_string().unwrap_or_default(),
+                    Err(RecvError::Lagged(_)) => String::new(),
+                    Err(RecvError::Closed) => return,
+                };
+                match weak.upgrade() {
+                    Some(cache) => cache.invalidate(&id),
+                    None => return,
+                }
+            }
+        });
+        let weak = Arc::downgrade(cache);
+        subscription.spawn(async move {
+            while reconnects.recv().await.is_ok() {
+                match weak.upgrade() {
+                    Some(cache) => cache.invalidate(""),
+                    None => return,
+                }
+            }
+        });
+        Ok(subscription)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(key: &str) -> Result<Session> {
+        let mut session = Session::new();
+        session.insert("key", key)?;
+        Ok(session)
+    }
+
+    #[test]
+    fn evicting_the_least_recently_used_session() -> Result {
+        let cache = SessionCache::new(2, Duration::from_secs(60));
+        let (first, second, third) = (session("first")?, session("second")?, session("third")?);
+        cache.insert(&first, 0)?;
+        cache.insert(&second, 0)?;
+        cache.get(first.id())?.unwrap();
+        cache.insert(&third, 0)?;
+
+        assert!(cache.get(second.id())?.is_none());
+        let cached = cache.get(first.id())?.unwrap();
+        assert_eq!(Some("first".to_string()), cached.get("key"));
+
+        let mut cached = cached;
+        cached.insert("key", "changed")?;
+        let cached = cache.get(first.id())?.unwrap();
+        assert_eq!(Some("first".to_string()), cached.get("key"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalidating_cached_sessions() -> Result {
+        let cache = SessionCache::new(2, Duration::from_secs(60));
+        let (first, second) = (session("first")?, session("second")?);
+        cache.insert(&first, 0)?;
+        cache.insert(&second, 0)?;
+
+        cache.invalidate(first.id());
+        assert!(cache.get(first.id())?.is_none());
+        assert!(cache.get(second.id())?.is_some());
+
+        cache.insert(&first, 0)?;
+        assert!(cache.get(first.id())?.is_none());
+        cache.invalidate("");
+        assert!(cache.get(second.id())?.is_none());
+
+        let cache = SessionCache::new(2, Duration::ZERO);
+        cache.insert(&first, 0)?;
+        assert!(cache.get(first.id())?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! deleting every session of a store in batches
+
+use async_session::Result;
+use fred::{
+    prelude::*,
+    types::{RedisKey, ScanType, Scanner},
+};
+use futures::stream::StreamExt;
+
+use crate::{RedisSessionStore, USER_KEY_PREFIX};
+
+/// how far clearing a store got, reported after every batch and returned
+/// once the store is empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ClearSummary {
+    /// number of keys deleted so far
+    pub deleted: usize,
+    /// number of batches deleted so far
+    pub batches: usize,
+}
+
+impl ClearSummary {
+    fn add_batch(&mut self, deleted: usize, progress: &mut impl FnMut(ClearSummary)) {
+        self.deleted += deleted;
+        self.batches += 1;
+        progress(*self);
+    }
+}
+
+impl RedisSessionStore {
+    /// deletes every session of this store, like
+    /// [`clear_with_progress`](Self::clear_with_progress) without a callback
+    pub async fn clear(&self) -> Result<ClearSummary> {
+        self.clear_with_progress(|_| {}).await
+    }
+
+    /// deletes every session of this store and the sets of sessions of its
+    /// users with `UNLINK`, in batches of the
+    /// [clear batch size](crate::RedisSessionStoreBuilder::clear_batch_size),
+    /// so redis is never blocked by a single huge command. `progress` is
+    /// called after every batch. locks and tombstones are left to expire
+    pub async fn clear_with_progress(
+        &self,
+        mut progress: impl FnMut(ClearSummary) + Send,
+    ) -> Result<ClearSummary> {
+        let mut summary = ClearSummary::default();
+        let batch_size = self.clear_batch_size;
+
+        match &self.index_key {
+            Some(index_key) => loop {
+                let ids: Vec<String> = self
+                    .pool
+                    .zrange(
+                        index_key,
+                        0,
+                        batch_size as i64 - 1,
+                        None,
+                        false,
+                        None,
+                        false,
+                    )
+                    .await?;
+                if ids.is_empty() {
+                    break;
+                }
+
+                let keys: Vec<RedisKey> = ids.iter().map(|id| self.prefix_key(id).into()).collect();
+                let deleted = self.pool.unlink(keys).await?;
+                self.pool.zrem::<(), _, _>(index_key, ids).await?;
+                summary.add_batch(deleted, &mut progress);
+            },
+            None => {
+                let pattern = self.prefix_key("*");
+                let scan_type = self.storage.scan_type();
+                // locks and tombstones share the type of sessions
+                let is_session = |key: &RedisKey| self.id_from_key(key).is_some();
+                self.unlink_scanned(pattern, scan_type, is_session, &mut summary, &mut progress)
+                    .await?;
+            }
+        }
+
+        let pattern = self.prefix_key(&format!("{USER_KEY_PREFIX}*"));
+        self.unlink_scanned(
+            pattern,
+            ScanType::ZSet,
+            |_| true,
+            &mut summary,
+            &mut progress,
+        )
+        .await?;
+        self.invalidate_cached("").await;
+
+        Ok(summary)
+    }
+
+    /// unlinks the keys of a type matching a pattern that pass `filter`, a
+    /// batch at a time
+    async fn unlink_scanned(
+        &self,
+        pattern: String,
+        scan_type: ScanType,
+        filter: impl Fn(&RedisKey) -> bool + Send,
+        summary: &mut ClearSummary,
+        progress: &mut (impl FnMut(ClearSummary) + Send),
+    ) -> Result {
+        let batch_size = self.clear_batch_size;
+        let mut batch = Vec::new();
+        let mut scanner = self.pool.scan(pattern, self.scan_count, Some(scan_type));
+        while let Some(page) = scanner.next().await {
+            let mut page = page?;
+            batch.extend(
+                page.take_results()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(&filter),
+            );
+            while batch.len() >= batch_size {
+                let keys: Vec<RedisKey> = batch.drain(..batch_size).collect();
+                summary.add_batch(self.pool.unlink(keys).await?, progress);
+            }
+            page.next()?;
+        }
+        if !batch.is_empty() {
+            summary.add_batch(self.pool.unlink(batch).await?, progress);
+        }
+
+        Ok(())
+    }
+}
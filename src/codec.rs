@@ -0,0 +1,139 @@
+use std::fmt::Debug;
+
+use async_session::{serde_json, Result, Session};
+
+/// converts sessions to and from the bytes stored in redis
+///
+/// [`JsonCodec`] is used unless another codec is set with
+/// [`RedisSessionStoreBuilder::codec`](crate::RedisSessionStoreBuilder::codec).
+/// changing the codec of an existing store makes the sessions written
+/// with the previous codec unreadable.
+///
+/// encoded sessions must not start with a byte below `0x10`, those are
+/// reserved for the headers the store adds around the encoded session
+pub trait SessionCodec: Debug + Send + Sync + 'static {
+    /// serializes a session into the bytes written to redis
+    fn encode(&self, session: &Session) -> Result<Vec<u8>>;
+
+    /// deserializes a session from the bytes read from redis
+    fn decode(&self, bytes: &[u8]) -> Result<Session>;
+}
+
+/// stores sessions as json, the format used by earlier versions of this crate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl SessionCodec for JsonCodec {
+    fn encode(&self, session: &Session) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(session)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Session> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// stores sessions as [MessagePack](https://msgpack.org), with struct
+/// fields encoded by name
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl SessionCodec for MessagePackCodec {
+    fn encode(&self, session: &Session) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(session)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Session> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// stores sessions as [CBOR](https://cbor.io)
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl SessionCodec for CborCodec {
+    fn encode(&self, session: &Session) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(session, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Session> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
+}
+
+/// stores sessions with [bincode](https://docs.rs/bincode). the format is
+/// not self-describing, so it is only readable by the same version of
+/// async-session that wrote it
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl SessionCodec for BincodeCodec {
+    fn encode(&self, session: &Session) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(session)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Session> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn roundtrip(codec: impl SessionCodec) -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.insert("nested", vec![("a", 1), ("b", 2)])?;
+        session.expire_in(Duration::from_secs(60));
+
+        let decoded = codec.decode(&codec.encode(&session)?)?;
+        assert_eq!(session.id(), decoded.id());
+        assert_eq!(session.expiry(), decoded.expiry());
+        assert_eq!(Some("value".to_string()), decoded.get("key"));
+        assert_eq!(session.get_raw("nested"), decoded.get_raw("nested"));
+        assert!(!decoded.data_changed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_roundtrip() -> Result {
+        roundtrip(JsonCodec)
+    }
+
+    #[test]
+    fn json_is_compatible_with_plain_serde_json() -> Result {
+        let session = Session::new();
+        assert_eq!(serde_json::to_vec(&session)?, JsonCodec.encode(&session)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_roundtrip() -> Result {
+        roundtrip(MessagePackCodec)
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_roundtrip() -> Result {
+        roundtrip(CborCodec)
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_roundtrip() -> Result {
+        roundtrip(BincodeCodec)
+    }
+}
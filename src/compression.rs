@@ -0,0 +1,115 @@
+use std::borrow::Cow;
+
+use async_session::Result;
+
+/// the first byte of a zstd compressed value
+const ZSTD: u8 = 0x01;
+/// the first byte of a lz4 compressed value
+const LZ4: u8 = 0x02;
+
+/// algorithm used to compress stored sessions that are larger than the
+/// [compression threshold](crate::RedisSessionStoreBuilder::compression_threshold)
+///
+/// compressed values start with a header byte naming the algorithm, values
+/// below the threshold are stored as they come out of the codec. the store
+/// reads both, so compression can be turned on or off for an existing store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// [zstd](https://facebook.github.io/zstd) at the given level, 1 to 22
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// [lz4](https://lz4.org) block compression
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    pub(crate) fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => {
+                let mut compressed = vec![ZSTD];
+                compressed.extend(zstd::bulk::compress(bytes, level)?);
+                Ok(compressed)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let mut compressed = vec![LZ4];
+                compressed.extend(lz4_flex::compress_prepend_size(bytes));
+                Ok(compressed)
+            }
+        }
+    }
+
+    pub(crate) fn is_valid_level(&self) -> bool {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => zstd::compression_level_range().contains(&level),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => true,
+        }
+    }
+}
+
+/// decompresses a stored value, returning it unchanged if it carries no
+/// compression header
+pub(crate) fn decompress(bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match bytes.first() {
+        #[cfg(feature = "zstd")]
+        Some(&ZSTD) => Ok(Cow::Owned(zstd::stream::decode_all(&bytes[1..])?)),
+        #[cfg(feature = "lz4")]
+        Some(&LZ4) => Ok(Cow::Owned(lz4_flex::decompress_size_prepended(
+            &bytes[1..],
+        )?)),
+        #[cfg(not(feature = "zstd"))]
+        Some(&ZSTD) => Err(disabled("zstd")),
+        #[cfg(not(feature = "lz4"))]
+        Some(&LZ4) => Err(disabled("lz4")),
+        _ => Ok(Cow::Borrowed(bytes)),
+    }
+}
+
+#[allow(dead_code)]
+fn disabled(feature: &str) -> async_session::Error {
+    async_session::Error::msg(format!(
+        "session is compressed with {feature} but the `{feature}` feature is disabled"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed_values_pass_through() -> Result {
+        let bytes = br#"{"id":"id","expiry":null,"data":{}}"#;
+        assert_eq!(&bytes[..], &*decompress(bytes)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_roundtrip() -> Result {
+        let bytes = "session".repeat(100).into_bytes();
+        let compressed = Compression::Zstd { level: 3 }.compress(&bytes)?;
+
+        assert_eq!(Some(&ZSTD), compressed.first());
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(bytes, &*decompress(&compressed)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_roundtrip() -> Result {
+        let bytes = "session".repeat(100).into_bytes();
+        let compressed = Compression::Lz4.compress(&bytes)?;
+
+        assert_eq!(Some(&LZ4), compressed.first());
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(bytes, &*decompress(&compressed)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use std::{borrow::Cow, collections::BTreeMap, fmt};
+
+use async_session::Result;
+
+/// the first byte of a value encrypted with aes-256-gcm
+const AES_256_GCM: u8 = 0x08;
+/// the first byte of a value encrypted with xchacha20-poly1305
+const XCHACHA20_POLY1305: u8 = 0x09;
+/// algorithm byte followed by the big endian key id
+const HEADER_LEN: usize = 5;
+
+/// a 256 bit key and the aead algorithm it is used with
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum EncryptionKey {
+    /// aes-256-gcm with a random 96 bit nonce per write
+    #[cfg(feature = "aes-gcm")]
+    Aes256Gcm([u8; 32]),
+    /// xchacha20-poly1305 with a random 192 bit nonce per write
+    #[cfg(feature = "chacha20poly1305")]
+    XChaCha20Poly1305([u8; 32]),
+}
+
+impl fmt::Debug for EncryptionKey {
+    #[cfg_attr(
+        not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+        allow(unused_variables)
+    )]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "aes-gcm")]
+            Self::Aes256Gcm(_) => f.write_str("Aes256Gcm(..)"),
+            #[cfg(feature = "chacha20poly1305")]
+            Self::XChaCha20Poly1305(_) => f.write_str("XChaCha20Poly1305(..)"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Cipher {
+    #[cfg(feature = "aes-gcm")]
+    Aes256Gcm(Box<aes_gcm::Aes256Gcm>),
+    #[cfg(feature = "chacha20poly1305")]
+    XChaCha20Poly1305(Box<chacha20poly1305::XChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(key: &EncryptionKey) -> Self {
+        match *key {
+            #[cfg(feature = "aes-gcm")]
+            EncryptionKey::Aes256Gcm(key) => {
+                use aes_gcm::KeyInit;
+                Self::Aes256Gcm(Box::new(aes_gcm::Aes256Gcm::new(&key.into())))
+            }
+            #[cfg(feature = "chacha20poly1305")]
+            EncryptionKey::XChaCha20Poly1305(key) => {
+                use chacha20poly1305::KeyInit;
+                Self::XChaCha20Poly1305(Box::new(chacha20poly1305::XChaCha20Poly1305::new(
+                    &key.into(),
+                )))
+            }
+        }
+    }
+
+    fn algorithm(&self) -> u8 {
+        match *self {
+            #[cfg(feature = "aes-gcm")]
+            Self::Aes256Gcm(_) => AES_256_GCM,
+            #[cfg(feature = "chacha20poly1305")]
+            Self::XChaCha20Poly1305(_) => XCHACHA20_POLY1305,
+        }
+    }
+
+    /// returns the nonce followed by the ciphertext
+    #[cfg_attr(
+        not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+        allow(unused_variables)
+    )]
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "aes-gcm")]
+            Self::Aes256Gcm(ref cipher) => {
+                use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+                let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+                let payload = Payload {
+                    msg: plaintext,
+                    aad,
+                };
+                let mut sealed = nonce.to_vec();
+                sealed.extend(cipher.encrypt(&nonce, payload).map_err(failed)?);
+                Ok(sealed)
+            }
+            #[cfg(feature = "chacha20poly1305")]
+            Self::XChaCha20Poly1305(ref cipher) => {
+                use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+                let nonce = chacha20poly1305::XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let payload = Payload {
+                    msg: plaintext,
+                    aad,
+                };
+                let mut sealed = nonce.to_vec();
+                sealed.extend(cipher.encrypt(&nonce, payload).map_err(failed)?);
+                Ok(sealed)
+            }
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+        allow(unused_variables)
+    )]
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "aes-gcm")]
+            Self::Aes256Gcm(ref cipher) => {
+                use aes_gcm::aead::{Aead, Payload};
+                if sealed.len() < 12 {
+                    return Err(failed(()));
+                }
+                let (nonce, msg) = sealed.split_at(12);
+                let payload = Payload { msg, aad };
+                cipher.decrypt(nonce.into(), payload).map_err(failed)
+            }
+            #[cfg(feature = "chacha20poly1305")]
+            Self::XChaCha20Poly1305(ref cipher) => {
+                use chacha20poly1305::aead::{Aead, Payload};
+                if sealed.len() < 24 {
+                    return Err(failed(()));
+                }
+                let (nonce, msg) = sealed.split_at(24);
+                let payload = Payload { msg, aad };
+                cipher.decrypt(nonce.into(), payload).map_err(failed)
+            }
+        }
+    }
+}
+
+/// the keys stored sessions are encrypted with
+///
+/// every key has a numeric id that is stored next to the ciphertext.
+/// sessions are always encrypted with the key with the highest id, and can
+/// be decrypted with any key in the keyring. to rotate keys add a new key
+/// with a higher id and keep the old ones until the sessions encrypted with
+/// them have been stored again or expired
+/// ```rust
+/// # #[cfg(feature = "aes-gcm")] {
+/// use async_fred_session::{EncryptionKey, Keyring};
+///
+/// let keyring = Keyring::new()
+///     .key(1, EncryptionKey::Aes256Gcm([1; 32]))
+///     .key(2, EncryptionKey::Aes256Gcm([2; 32]));
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: BTreeMap<u32, Cipher>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// creates an empty keyring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a key with the given id, replacing any key with the same id
+    // without an aead feature there are no keys to add
+    #[cfg_attr(
+        not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+        allow(unreachable_code)
+    )]
+    pub fn key(mut self, id: u32, key: EncryptionKey) -> Self {
+        self.keys.insert(id, Cipher::new(&key));
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// encrypts a value with the newest key. `aad` is authenticated but not
+    /// stored, the same bytes have to be passed to [`decrypt`]
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let Some((id, cipher)) = self.keys.last_key_value() else {
+            return Err(async_session::Error::msg("keyring has no keys"));
+        };
+
+        let mut encrypted = Vec::with_capacity(HEADER_LEN + plaintext.len() + 40);
+        encrypted.push(cipher.algorithm());
+        encrypted.extend(id.to_be_bytes());
+        encrypted.extend(cipher.seal(aad, plaintext)?);
+        Ok(encrypted)
+    }
+}
+
+/// decrypts a stored value, returning it unchanged if it is not encrypted
+/// and either there is no keyring or `allow_unencrypted` is set
+pub(crate) fn decrypt<'a>(
+    keyring: Option<&Keyring>,
+    allow_unencrypted: bool,
+    aad: &[u8],
+    bytes: &'a [u8],
+) -> Result<Cow<'a, [u8]>> {
+    let algorithm = match bytes.first() {
+        Some(&algorithm) if algorithm == AES_256_GCM || algorithm == XCHACHA20_POLY1305 => {
+            algorithm
+        }
+        _ if keyring.is_none() || allow_unencrypted => return Ok(Cow::Borrowed(bytes)),
+        _ => {
+            return Err(async_session::Error::msg(
+                "session is not encrypted but the store has a keyring",
+            ))
+        }
+    };
+
+    let Some(keyring) = keyring else {
+        return Err(async_session::Error::msg(
+            "session is encrypted but the store has no keyring",
+        ));
+    };
+    if bytes.len() < HEADER_LEN {
+        return Err(failed(()));
+    }
+
+    let id = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    match keyring.keys.get(&id) {
+        Some(cipher) if cipher.algorithm() == algorithm => {
+            Ok(Cow::Owned(cipher.open(aad, &bytes[HEADER_LEN..])?))
+        }
+        Some(_) => Err(async_session::Error::msg(format!(
+            "session was encrypted with key {id} using a different algorithm"
+        ))),
+        None => Err(async_session::Error::msg(format!(
+            "session was encrypted with key {id}, which is not in the keyring"
+        ))),
+    }
+}
+
+fn failed<E>(_: E) -> async_session::Error {
+    async_session::Error::msg("session encryption or decryption failed")
+}
+
+#[cfg(all(test, feature = "aes-gcm", feature = "chacha20poly1305"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unencrypted_values_pass_through_unless_rejected() -> Result {
+        let bytes = br#"{"id":"id","expiry":null,"data":{}}"#;
+        assert_eq!(&bytes[..], &*decrypt(None, false, b"id", bytes)?);
+
+        let keyring = Keyring::new().key(1, EncryptionKey::Aes256Gcm([1; 32]));
+        assert!(decrypt(Some(&keyring), false, b"id", bytes).is_err());
+        assert_eq!(&bytes[..], &*decrypt(Some(&keyring), true, b"id", bytes)?);
+        Ok(())
+    }
+
+    #[test]
+    fn encrypting_with_the_newest_key() -> Result {
+        let keyring = Keyring::new()
+            .key(7, EncryptionKey::XChaCha20Poly1305([7; 32]))
+            .key(3, EncryptionKey::Aes256Gcm([3; 32]));
+
+        let encrypted = keyring.encrypt(b"id", b"session")?;
+        assert_eq!(&[XCHACHA20_POLY1305, 0, 0, 0, 7], &encrypted[..HEADER_LEN]);
+        assert_eq!(
+            b"session",
+            &*decrypt(Some(&keyring), false, b"id", &encrypted)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decrypting_after_a_rotation() -> Result {
+        let old = Keyring::new().key(1, EncryptionKey::Aes256Gcm([1; 32]));
+        let encrypted = old.encrypt(b"id", b"session")?;
+
+        let rotated = old.clone().key(2, EncryptionKey::Aes256Gcm([2; 32]));
+        assert_eq!(
+            b"session",
+            &*decrypt(Some(&rotated), false, b"id", &encrypted)?
+        );
+        assert_eq!(
+            &[AES_256_GCM, 0, 0, 0, 2],
+            &rotated.encrypt(b"id", b"")?[..HEADER_LEN]
+        );
+
+        let retired = Keyring::new().key(2, EncryptionKey::Aes256Gcm([2; 32]));
+        assert!(decrypt(Some(&retired), false, b"id", &encrypted).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decrypting_with_the_wrong_aad_fails() -> Result {
+        let keyring = Keyring::new().key(1, EncryptionKey::Aes256Gcm([1; 32]));
+        let encrypted = keyring.encrypt(b"id", b"session")?;
+
+        assert!(decrypt(Some(&keyring), false, b"other id", &encrypted).is_err());
+        assert!(decrypt(None, false, b"id", &encrypted).is_err());
+        Ok(())
+    }
+}
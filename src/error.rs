@@ -0,0 +1,48 @@
+use std::fmt;
+
+/// errors specific to [`RedisSessionStore`](crate::RedisSessionStore)
+///
+/// these are returned wrapped in [`async_session::Error`] by the store
+/// methods, use [`async_session::Error::downcast_ref`] to inspect them
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// the builder was given an invalid option or combination of options
+    InvalidConfig(String),
+    /// the operation is not available with the configured options
+    Unsupported(String),
+    /// the user with this id already has as many sessions as the
+    /// [session limit](crate::RedisSessionStoreBuilder::session_limit) allows
+    SessionLimitReached(String),
+    /// the session with this id was stored by someone else since it was
+    /// loaded, with [versioned sessions](crate::RedisSessionStoreBuilder::versioned_sessions)
+    VersionConflict(String),
+    /// the session with this id is [locked](crate::RedisSessionStore::lock)
+    SessionLocked(String),
+    /// a new or regenerated session could not be stored because a session
+    /// with this id already exists, even after giving it new ids
+    IdCollision(String),
+    /// the session with this id was destroyed and has a
+    /// [tombstone](crate::RedisSessionStoreBuilder::tombstones)
+    SessionDestroyed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidConfig(reason) => write!(f, "invalid session store config: {reason}"),
+            Self::Unsupported(reason) => write!(f, "unsupported operation: {reason}"),
+            Self::SessionLimitReached(user_id) => {
+                write!(f, "user {user_id} has reached the session limit")
+            }
+            Self::VersionConflict(id) => {
+                write!(f, "session {id} was changed since it was loaded")
+            }
+            Self::SessionLocked(id) => write!(f, "session {id} is locked"),
+            Self::IdCollision(id) => write!(f, "a session with id {id} already exists"),
+            Self::SessionDestroyed(id) => write!(f, "session {id} was destroyed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,166 @@
+//! session lifecycle events from redis keyspace notifications
+
+use std::future::Future;
+
+use async_session::Result;
+use fred::{
+    clients::SubscriberClient,
+    interfaces::{ClientLike, ConfigInterface, PubsubInterface},
+    prelude::RedisClient,
+    types::{Message, RedisKey},
+};
+use futures::{stream, Stream};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use crate::{Error, RedisSessionStore};
+
+/// the `notify-keyspace-events` flags needed for session events: keyevent
+/// notifications of generic commands and of expired keys
+const NOTIFY_FLAGS: &str = "Egx";
+
+/// what happened to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionEventKind {
+    /// the session expired
+    Expired,
+    /// the session was deleted, for example when it was destroyed
+    Deleted,
+}
+
+/// a session that expired or was deleted
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SessionEvent {
+    pub id: String,
+    pub kind: SessionEventKind,
+}
+
+/// a dedicated pub/sub connection with the tasks consuming it, closed when
+/// dropped
+pub(crate) struct Subscription {
+    pub(crate) client: SubscriberClient,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Subscription {
+    /// runs a task until the subscription is dropped
+    pub(crate) fn spawn(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.push(tokio::spawn(task));
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            runtime.spawn(async move {
+                let _ = client.quit().await;
+            });
+        }
+    }
+}
+
+impl RedisSessionStore {
+    /// subscribes to the sessions of this store that expire or are deleted,
+    /// using a dedicated connection that reconnects and subscribes again on
+    /// its own. events are missed while it is disconnected.
+    ///
+    /// relies on redis keyspace notifications, which have to be enabled with
+    /// the `Egx` flags of `notify-keyspace-events`, or by the store with
+    /// [`keyspace_notifications`](crate::RedisSessionStoreBuilder::keyspace_notifications).
+    /// in a cluster, only the events of the node the connection goes to are
+    /// received. requires a key prefix, to tell sessions apart from the other
+    /// keys on the server
+    pub async fn session_events(
+        &self,
+    ) -> Result<impl Stream<Item = SessionEvent> + Send + 'static> {
+        if self.prefix.is_none() {
+            return Err(Error::Unsupported("session events require a key prefix".into()).into());
+        }
+        if self.keyspace_notifications {
+            enable_notifications(self.pool.next()).await?;
+        }
+
+        let subscription = self.subscription().await?;
+        let db = subscription.client.client_config().database.unwrap_or(0);
+        let messages = subscription.client.on_message();
+        subscription
+            .client
+            .subscribe::<(), _>(vec![
+                format!("__keyevent@{db}__:expired"),
+                format!("__keyevent@{db}__:del"),
+            ])
+            .await?;
+
+        let store = self.clone();
+        Ok(stream::unfold(
+            (messages, subscription),
+            move |(mut messages, subscription)| {
+                let store = store.clone();
+                async move {
+                    loop {
+                        match messages.recv().await {
+                            Ok(message) => {
+                                if let Some(event) = store.session_event(message) {
+                                    return Some((event, (messages, subscription)));
+                                }
+                            }
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => return None,
+                        }
+                    }
+                }
+            },
+        ))
+    }
+
+    /// connects a dedicated pub/sub connection configured like the pool,
+    /// which reconnects and subscribes again on its own
+    pub(crate) async fn subscription(&self) -> Result<Subscription> {
+        let pool_client = self.pool.next();
+        let policy = pool_client.client_reconnect_policy().unwrap_or_default();
+        let client = SubscriberClient::new(
+            pool_client.client_config(),
+            Some(pool_client.perf_config()),
+            Some(policy),
+        );
+        client.connect();
+        client.wait_for_connect().await?;
+        let resubscribe = client.manage_subscriptions();
+        Ok(Subscription {
+            client,
+            tasks: vec![resubscribe],
+        })
+    }
+
+    /// the session event of a keyevent notification, `None` for keys that
+    /// are not sessions of this store
+    fn session_event(&self, message: Message) -> Option<SessionEvent> {
+        let kind = match message.channel.rsplit(':').next()? {
+            "expired" => SessionEventKind::Expired,
+            "del" => SessionEventKind::Deleted,
+            _ => return None,
+        };
+        let key = RedisKey::from(message.value.as_str()?.as_ref());
+        let id = self.id_from_key(&key)?;
+        Some(SessionEvent { id, kind })
+    }
+}
+
+/// adds the flags needed for session events to `notify-keyspace-events`
+async fn enable_notifications(client: &RedisClient) -> Result {
+    let (_, mut flags): (String, String) = client.config_get("notify-keyspace-events").await?;
+    let missing: String = NOTIFY_FLAGS
+        .chars()
+        .filter(|flag| !flags.contains(*flag))
+        .collect();
+    if !missing.is_empty() {
+        flags.push_str(&missing);
+        client.config_set("notify-keyspace-events", flags).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+//! iterating over and paging through every session of a store without
+//! loading them all at once
+
+use std::collections::HashMap;
+
+use async_session::{chrono::Utc, Result, Session};
+use fred::{
+    bytes::Bytes,
+    prelude::*,
+    types::{ClusterHash, CustomCommand, RedisKey, Scanner},
+};
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::{storage, RedisSessionStore, StorageMode};
+
+/// a page of sessions or session ids, see [`RedisSessionStore::list_sessions`]
+#[derive(Debug, Clone)]
+pub struct SessionPage<T> {
+    /// the sessions or ids on this page
+    pub items: Vec<T>,
+    /// the cursor to pass to get the next page, `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl RedisSessionStore {
+    /// streams the ids of all sessions in this store, one `SCAN` page at a
+    /// time, or one `ZSCAN` page of the
+    /// [session index](crate::RedisSessionStoreBuilder::session_index).
+    /// sessions stored or deleted while the stream is running may or may
+    /// not be included
+    pub fn session_ids(&self) -> impl Stream<Item = Result<String>> + Send + '_ {
+        self.id_pages().flat_map(flatten)
+    }
+
+    /// streams all live sessions in this store. every page of ids is
+    /// fetched with a single `MGET`, or a pipeline of `HGETALL` in
+    /// [`StorageMode::Hash`], so memory use does not grow with the store.
+    /// sessions that expired or were deleted since their id was read are
+    /// skipped
+    pub fn sessions(&self) -> impl Stream<Item = Result<Session>> + Send + '_ {
+        self.id_pages()
+            .then(move |ids| async move {
+                match ids {
+                    Ok(ids) => self.fetch_sessions(ids).await,
+                    Err(error) => Err(error),
+                }
+            })
+            .flat_map(flatten_results)
+    }
+
+    /// lists the ids of the sessions in this store one page at a time. pass
+    /// `None` to get the first page and the returned
+    /// [`next_cursor`](SessionPage::next_cursor) to get the following ones.
+    ///
+    /// pages are read with `SCAN`, or `ZSCAN` on the
+    /// [session index](crate::RedisSessionStoreBuilder::session_index), and
+    /// hold at least `page_size` ids except for the last one, but may hold a
+    /// few more. like with `SCAN`, an id may show up on more than one page.
+    /// in a cluster the session index is required
+    pub async fn list_session_ids(
+        &self,
+        cursor: Option<&str>,
+        page_size: u32,
+    ) -> Result<SessionPage<String>> {
+        let mut cursor = cursor.unwrap_or("0").to_string();
+        let mut items = Vec::new();
+
+        loop {
+            let (next, ids) = self.scan_once(&cursor, page_size.max(1)).await?;
+            items.extend(ids);
+            cursor = next;
+            if cursor == "0" {
+                return Ok(SessionPage {
+                    items,
+                    next_cursor: None,
+                });
+            }
+            if items.len() >= page_size as usize {
+                return Ok(SessionPage {
+                    items,
+                    next_cursor: Some(cursor),
+                });
+            }
+        }
+    }
+
+    /// lists the live sessions in this store one page at a time, like
+    /// [`list_session_ids`](Self::list_session_ids). sessions that expired
+    /// or were deleted since their id was read are left out, so pages may
+    /// hold fewer than `page_size` sessions
+    pub async fn list_sessions(
+        &self,
+        cursor: Option<&str>,
+        page_size: u32,
+    ) -> Result<SessionPage<Session>> {
+        let page = self.list_session_ids(cursor, page_size).await?;
+        Ok(SessionPage {
+            items: self
+                .fetch_sessions(page.items)
+                .await?
+                .into_iter()
+                .collect::<Result<_>>()?,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// runs a single `SCAN`, or `ZSCAN` on the index, returning the next
+    /// cursor and the session ids it found
+    async fn scan_once(&self, cursor: &str, count: u32) -> Result<(String, Vec<String>)> {
+        match &self.index_key {
+            Some(index_key) => {
+                let zscan = CustomCommand::new_static("ZSCAN", ClusterHash::FirstKey, false);
+                let args: Vec<RedisValue> = vec![
+                    index_key.as_str().into(),
+                    cursor.into(),
+                    "COUNT".into(),
+                    count.into(),
+                ];
+                let (cursor, entries): (String, Vec<String>) =
+                    self.pool.custom(zscan, args).await?;
+
+                let now_millis = Utc::now().timestamp_millis() as f64;
+                let ids = entries
+                    .chunks_exact(2)
+                    .filter(|entry| entry[1].parse().is_ok_and(|score: f64| score >= now_millis))
+                    .map(|entry| entry[0].clone())
+                    .collect();
+                Ok((cursor, ids))
+            }
+            None => {
+                let scan = CustomCommand::new_static("SCAN", ClusterHash::Random, false);
+                let args: Vec<RedisValue> = vec![
+                    cursor.into(),
+                    "MATCH".into(),
+                    self.prefix_key("*").into(),
+                    "COUNT".into(),
+                    count.into(),
+                    "TYPE".into(),
+                    self.storage.type_name().into(),
+                ];
+                let (cursor, keys): (String, Vec<RedisKey>) = self.pool.custom(scan, args).await?;
+                let ids = keys
+                    .iter()
+                    .filter_map(|key| self.id_from_key(key))
+                    .collect();
+                Ok((cursor, ids))
+            }
+        }
+    }
+
+    /// streams the ids of the sessions in this store a page at a time
+    pub(crate) fn id_pages(&self) -> impl Stream<Item = Result<Vec<String>>> + Send + '_ {
+        match &self.index_key {
+            Some(index_key) => self
+                .pool
+                .zscan(index_key, "*", self.scan_count)
+                .map(move |page| {
+                    let mut page = page?;
+                    let now_millis = Utc::now().timestamp_millis() as f64;
+                    let ids = page
+                        .take_results()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|(_, score)| *score >= now_millis)
+                        .filter_map(|(id, _)| id.into_string())
+                        .collect();
+                    page.next()?;
+                    Ok(ids)
+                })
+                .boxed(),
+            None => self
+                .pool
+                .scan(
+                    self.prefix_key("*"),
+                    self.scan_count,
+                    Some(self.storage.scan_type()),
+                )
+                .map(move |page| {
+                    let mut page = page?;
+                    let ids = page
+                        .take_results()
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|key| self.id_from_key(key))
+                        .collect();
+                    page.next()?;
+                    Ok(ids)
+                })
+                .boxed(),
+        }
+    }
+
+    /// the session id stored at `key`, `None` for keys of the store itself
+    pub(crate) fn id_from_key(&self, key: &RedisKey) -> Option<String> {
+        let key = key.as_str()?;
+        let id = match &self.prefix {
+            Some(prefix) => key.strip_prefix(prefix.as_str())?,
+            None => key,
+        };
+        (!storage::is_reserved(id)).then(|| id.to_string())
+    }
+
+    pub(crate) async fn fetch_sessions(&self, ids: Vec<String>) -> Result<Vec<Result<Session>>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = ids.iter().map(|id| self.prefix_key(id)).collect();
+        let now_millis = Utc::now().timestamp_millis();
+
+        let sessions = match self.storage {
+            StorageMode::String => {
+                let values: Vec<Option<Bytes>> = self.pool.mget(keys).await?;
+                ids.iter()
+                    .zip(values)
+                    .filter_map(|(id, value)| Some(self.decode(id, &value?)))
+                    .collect::<Vec<_>>()
+            }
+            StorageMode::Hash => {
+                let pipeline = self.pool.next().pipeline();
+                for key in keys {
+                    pipeline.hgetall::<(), _>(key).await?;
+                }
+                let hashes: Vec<HashMap<String, String>> = pipeline.all().await?;
+                hashes
+                    .into_iter()
+                    .filter_map(|fields| storage::from_fields(fields).transpose())
+                    .collect()
+            }
+        };
+
+        Ok(sessions
+            .into_iter()
+            .filter(
+                |session| !matches!(session, Ok(session) if self.is_expired(session, now_millis)),
+            )
+            .collect())
+    }
+}
+
+fn flatten<T>(page: Result<Vec<T>>) -> impl Stream<Item = Result<T>> {
+    flatten_results(page.map(|items| items.into_iter().map(Ok).collect()))
+}
+
+fn flatten_results<T>(page: Result<Vec<Result<T>>>) -> impl Stream<Item = Result<T>> {
+    match page {
+        Ok(items) => stream::iter(items),
+        Err(error) => stream::iter(vec![Err(error)]),
+    }
+}
@@ -1,5 +1,7 @@
 //! # async-fred-session
 //! Redis backed session store for async-session using fred.rs.
+//! Session expiry is sent to redis with millisecond precision using
+//! `PXAT`, so redis 6.2 or newer is required.
 //! ```rust
 //! # #[tokio::main(flavor = "current_thread")]
 //! # async fn main() {
@@ -22,23 +24,126 @@
 //! assert_eq!(&session.get::<String>("key").unwrap(), "value");
 //! # }
 //! ```
+//!
+//! ## cargo features
+//! - `msgpack`: `MessagePackCodec` for storing sessions as MessagePack
+//! - `cbor`: `CborCodec` for storing sessions as CBOR
+//! - `bincode`: `BincodeCodec` for storing sessions with bincode
+//! - `zstd`: `Compression::Zstd` for compressing large sessions with zstd
+//! - `lz4`: `Compression::Lz4` for compressing large sessions with lz4
+//! - `aes-gcm`: `EncryptionKey::Aes256Gcm` for encrypting sessions with aes-256-gcm
+//! - `chacha20poly1305`: `EncryptionKey::XChaCha20Poly1305` for encrypting
+//!   sessions with xchacha20-poly1305
 
 #![forbid(unsafe_code, future_incompatible)]
 
+mod builder;
+mod cache;
+mod clear;
+mod codec;
+mod compression;
+mod encryption;
+mod error;
+mod events;
+mod iter;
+mod lock;
+mod merge;
+mod meta;
+mod regenerate;
+mod scripts;
+mod storage;
+mod timeout;
+mod tombstone;
+mod user;
+mod version;
+
+pub use builder::RedisSessionStoreBuilder;
+pub use clear::ClearSummary;
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use codec::CborCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePackCodec;
+pub use codec::{JsonCodec, SessionCodec};
+pub use compression::Compression;
+pub use encryption::{EncryptionKey, Keyring};
+pub use error::Error;
+pub use events::{SessionEvent, SessionEventKind};
 pub use fred;
+pub use iter::SessionPage;
+pub use lock::SessionLock;
+pub use merge::MergeStrategy;
+pub use storage::StorageMode;
+pub use timeout::TimeoutPolicy;
+pub use user::SessionLimitPolicy;
 
-use async_session::{async_trait, serde_json, Result, Session, SessionStore};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_session::{
+    async_trait,
+    chrono::Utc,
+    serde::{de::DeserializeOwned, Serialize},
+    serde_json, Result, Session, SessionStore,
+};
+use cache::SessionCache;
 use fred::{
+    bytes::Bytes,
     pool::RedisPool,
     prelude::*,
-    types::{RedisKey, ScanType, Scanner},
+    types::{ClusterHash, CustomCommand},
 };
 use futures::stream::StreamExt;
+use tombstone::TombstoneAudit;
+
+/// what [`SessionStore::store_session`] does with a session that has not
+/// changed since it was loaded
+///
+/// a session counts as changed if it is new, if any of its keys were
+/// inserted or removed, or if its expiry was changed. to tell whether the
+/// expiry changed, the store keeps the expiry it last wrote in the session
+/// data under the reserved `__afs:stored_expiry` key
+///
+/// unchanged sessions that are not written keep the encryption key they
+/// were written with until they change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnchangedSessions {
+    /// write every session, changed or not
+    #[default]
+    Write,
+    /// leave unchanged sessions as they are in redis
+    Skip,
+    /// only update the ttl of unchanged sessions, so that the default and
+    /// maximum ttl are counted from the last time the session was stored
+    RefreshTtl,
+}
 
 #[derive(Clone)]
 pub struct RedisSessionStore {
     pool: RedisPool,
     prefix: Option<String>,
+    default_ttl: Option<Duration>,
+    max_ttl: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    timeout_policy: Option<TimeoutPolicy>,
+    scan_count: Option<u32>,
+    clear_batch_size: usize,
+    clock_skew_tolerance: Duration,
+    codec: Arc<dyn SessionCodec>,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    keyring: Option<Arc<Keyring>>,
+    allow_unencrypted: bool,
+    storage: StorageMode,
+    unchanged_sessions: UnchangedSessions,
+    index_key: Option<String>,
+    session_limit: Option<(u32, SessionLimitPolicy)>,
+    versioned_sessions: bool,
+    merge_strategy: MergeStrategy,
+    tombstone_ttl: Option<Duration>,
+    tombstone_audit: Option<TombstoneAudit>,
+    keyspace_notifications: bool,
+    cache: Option<Arc<SessionCache>>,
 }
 
 impl std::fmt::Debug for RedisSessionStore {
@@ -64,30 +169,244 @@ impl RedisSessionStore {
     /// # }
     /// ```
     pub fn from_pool(pool: RedisPool, prefix: Option<String>) -> Self {
-        Self { pool, prefix }
+        match prefix {
+            None => Self::builder(pool).into_store(),
+            Some(prefix) => Self::builder(pool).prefix(prefix).into_store(),
+        }
+    }
+
+    /// creates a [`RedisSessionStoreBuilder`] for configuring a store
+    /// beyond its key prefix
+    pub fn builder(pool: RedisPool) -> RedisSessionStoreBuilder {
+        RedisSessionStoreBuilder::new(pool)
     }
 
     /// returns the number of sessions in this store
     pub async fn count(&self) -> Result<usize> {
-        match self.prefix {
-            None => Ok(self.pool.dbsize().await?),
-            Some(_) => Ok(self.ids().await?.map_or(0, |v| v.len())),
+        match &self.index_key {
+            Some(index_key) => {
+                let now_millis = Utc::now().timestamp_millis();
+                Ok(scripts::count_index()
+                    .evalsha_with_reload(self.pool.next(), index_key, now_millis)
+                    .await?)
+            }
+            None => {
+                let mut count = 0;
+                let mut pages = self.id_pages();
+                while let Some(ids) = pages.next().await {
+                    count += ids?.len();
+                }
+                Ok(count)
+            }
         }
     }
 
-    async fn ids(&self) -> Result<Option<Vec<RedisKey>>> {
-        let mut result = Vec::new();
-        let mut scanner = self
-            .pool
-            .scan(self.prefix_key("*"), None, Some(ScanType::String));
+    /// deletes every key in every database of the redis server with
+    /// `FLUSHALL`, whether it was written by this store or not.
+    /// [`SessionStore::clear_store`] only deletes the sessions of this store
+    pub async fn flush_all_databases(&self) -> Result {
+        Ok(self.pool.flushall(false).await?)
+    }
+
+    /// reads a single key of the session with the given id without loading
+    /// the whole session. only available in [`StorageMode::Hash`]
+    pub async fn get_field<T: DeserializeOwned>(&self, id: &str, key: &str) -> Result<Option<T>> {
+        self.check_field_access(key)?;
+        self.pool
+            .hget::<Option<String>, _, _>(self.prefix_key(id), key)
+            .await?
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// sets a single key of the session with the given id without loading
+    /// the whole session. returns `false` without writing anything if the
+    /// session does not exist. only available in [`StorageMode::Hash`]
+    pub async fn set_field(&self, id: &str, key: &str, value: impl Serialize) -> Result<bool> {
+        self.check_field_access(key)?;
+        let value = serde_json::to_string(&value)?;
+        let mut args = vec![key, &value];
+        if self.versioned_sessions {
+            args.push("bump");
+        }
+        let exists = scripts::set_field_if_exists()
+            .evalsha_with_reload(self.pool.next(), self.prefix_key(id), args)
+            .await?;
+        self.invalidate_cached(id).await;
+        Ok(exists)
+    }
+
+    /// removes a single key of the session with the given id without loading
+    /// the whole session. returns whether the key existed. only available in
+    /// [`StorageMode::Hash`]
+    pub async fn remove_field(&self, id: &str, key: &str) -> Result<bool> {
+        self.check_field_access(key)?;
+        let mut args = vec![key];
+        if self.versioned_sessions {
+            args.push("bump");
+        }
+        let removed = scripts::remove_field()
+            .evalsha_with_reload(self.pool.next(), self.prefix_key(id), args)
+            .await?;
+        self.invalidate_cached(id).await;
+        Ok(removed)
+    }
+
+    fn check_field_access(&self, key: &str) -> std::result::Result<(), Error> {
+        if self.storage != StorageMode::Hash {
+            return Err(Error::Unsupported(
+                "field access requires hash storage mode".into(),
+            ));
+        }
+        if storage::is_reserved(key) {
+            return Err(Error::Unsupported(format!(
+                "session key `{key}` is reserved in hash storage mode"
+            )));
+        }
+        Ok(())
+    }
+
+    /// the unix timestamp in milliseconds at which redis should drop the
+    /// session, taking the configured default ttl, idle timeout and maximum
+    /// ttl into account. `None` means the session is kept indefinitely
+    fn expires_at_millis(&self, session: &Session, now_millis: i64) -> Option<i64> {
+        let after = |ttl: Duration| now_millis.saturating_add(millis(ttl));
+        let expiry = session.expiry().map(|expiry| expiry.timestamp_millis());
+        let expires_at =
+            earliest(expiry, self.idle_timeout.map(after)).or_else(|| self.default_ttl.map(after));
+        let policy = self
+            .timeout_policy
+            .and_then(|policy| policy.expires_at_millis(session));
+
+        earliest(earliest(expires_at, policy), self.max_ttl.map(after))
+    }
 
-        while let Some(res) = scanner.next().await {
-            if let Some(keys) = res?.take_results() {
-                result.extend_from_slice(&keys);
+    /// the keys passed to the scripts that touch the session with the given
+    /// id, and the first argument naming the optional ones
+    fn script_keys(&self, id: &str, user_id: Option<&str>) -> (Vec<String>, String) {
+        let mut keys = vec![self.prefix_key(id)];
+        let mut layout = String::new();
+        if let Some(index_key) = &self.index_key {
+            keys.push(index_key.clone());
+            layout.push('i');
+        }
+        if let Some(user_id) = user_id {
+            keys.push(self.user_key(user_id));
+            layout.push('u');
+        }
+        if self.tombstone_ttl.is_some() {
+            keys.push(self.tombstone_key(id));
+            layout.push('t');
+        }
+        (keys, layout)
+    }
+
+    /// the arguments of the scripts that read a session and expire it after
+    /// the idle timeout
+    fn sliding_args(layout: &str, id: &str, idle: Duration) -> Vec<String> {
+        let idle = millis(idle);
+        let score = Utc::now().timestamp_millis().saturating_add(idle);
+        vec![
+            layout.to_string(),
+            idle.to_string(),
+            id.to_string(),
+            score.to_string(),
+        ]
+    }
+
+    /// reads the value of a session in string storage mode, refreshing its
+    /// ttl if sliding expiration is enabled
+    async fn get_value(&self, id: &str, key: &str) -> Result<Option<Bytes>> {
+        let Some(idle) = self.idle_timeout else {
+            return Ok(self.pool.get(key).await?);
+        };
+
+        // getex cannot update the index, so indexed stores use the script
+        if self.index_key.is_none() {
+            let idle = millis(idle).to_string();
+            let getex = CustomCommand::new_static("GETEX", ClusterHash::FirstKey, false);
+            let args: Vec<RedisValue> = vec![key.into(), "PX".into(), idle.as_str().into()];
+            return Ok(self.pool.custom(getex, args).await?);
+        }
+
+        let (keys, layout) = self.script_keys(id, None);
+        Ok(scripts::get_and_expire()
+            .evalsha_with_reload(
+                self.pool.next(),
+                keys,
+                Self::sliding_args(&layout, id, idle),
+            )
+            .await?)
+    }
+
+    /// reads the fields of a session in hash storage mode, refreshing its
+    /// ttl if sliding expiration is enabled
+    async fn get_fields(&self, id: &str, key: &str) -> Result<HashMap<String, String>> {
+        let Some(idle) = self.idle_timeout else {
+            return Ok(self.pool.hgetall(key).await?);
+        };
+
+        let (keys, layout) = self.script_keys(id, None);
+        Ok(scripts::hgetall_and_expire()
+            .evalsha_with_reload(
+                self.pool.next(),
+                keys,
+                Self::sliding_args(&layout, id, idle),
+            )
+            .await?)
+    }
+
+    /// whether the session expiry or a limit of the timeout policy has
+    /// passed, allowing for the configured clock skew tolerance
+    fn is_expired(&self, session: &Session, now_millis: i64) -> bool {
+        let tolerance = millis(self.clock_skew_tolerance);
+        let expiry = session.expiry().map(|expiry| expiry.timestamp_millis());
+        let policy = self
+            .timeout_policy
+            .and_then(|policy| policy.expires_at_millis(session));
+
+        earliest(expiry, policy)
+            .is_some_and(|expires_at| expires_at.saturating_add(tolerance) < now_millis)
+    }
+
+    /// serializes a session into the value written to redis
+    fn encode(&self, session: &Session) -> Result<Bytes> {
+        let mut bytes = self.codec.encode(session)?;
+        if let Some(compression) = &self.compression {
+            if bytes.len() > self.compression_threshold {
+                bytes = compression.compress(&bytes)?;
             }
         }
+        if let Some(keyring) = &self.keyring {
+            bytes = keyring.encrypt(session.id().as_bytes(), &bytes)?;
+        }
 
-        Ok((!result.is_empty()).then_some(result))
+        Ok(bytes.into())
+    }
+
+    /// serializes a session into the value stored in string storage mode,
+    /// behind its version header with versioned sessions
+    fn encode_stored(&self, session: &Session) -> Result<Bytes> {
+        let value = self.encode(session)?;
+        match meta::version(session) {
+            Some(version) if self.versioned_sessions => {
+                Ok([&version::header(version)[..], &value].concat().into())
+            }
+            _ => Ok(value),
+        }
+    }
+
+    /// deserializes the session with the given id from a value read from redis
+    fn decode(&self, id: &str, bytes: &[u8]) -> Result<Session> {
+        let bytes = version::strip(bytes);
+        let bytes = encryption::decrypt(
+            self.keyring.as_deref(),
+            self.allow_unencrypted,
+            id.as_bytes(),
+            bytes,
+        )?;
+        self.codec.decode(&compression::decompress(&bytes)?)
     }
 
     fn prefix_key(&self, key: &str) -> String {
@@ -101,61 +420,355 @@ impl RedisSessionStore {
     async fn ttl_for_session(&self, session: &Session) -> Result<usize> {
         Ok(self.pool.ttl(self.prefix_key(session.id())).await?)
     }
+
+    #[cfg(test)]
+    async fn pttl_for_session(&self, session: &Session) -> Result<i64> {
+        Ok(self.pool.pttl(self.prefix_key(session.id())).await?)
+    }
+
+    /// writes a session with the `store_string` or `store_hash` script,
+    /// returning 1 if it was stored, 0 if the session limit was reached, -1
+    /// on a version conflict, -2 if a new session's id is taken and -3 if the
+    /// session has a tombstone. sessions evicted by the session limit are
+    /// dropped from the caches
+    async fn write_session(
+        &self,
+        session: &mut Session,
+        keys: Vec<String>,
+        layout: &str,
+        expiration: &str,
+        now_millis: i64,
+        is_new: bool,
+    ) -> Result<i64> {
+        let id = session.id().to_string();
+        // the version the stored session has to be at, 0 for a new session
+        // which must not exist yet. existing sessions are only checked with
+        // versioned sessions, and not if stored before versioning was enabled
+        let version = if is_new {
+            Some(0)
+        } else {
+            meta::version(session)
+        };
+        let expected_version = if self.versioned_sessions {
+            meta::set(
+                session,
+                meta::VERSION,
+                (version.unwrap_or(0) + 1).to_string(),
+            );
+            version
+        } else {
+            is_new.then_some(0)
+        };
+
+        let (limit, on_limit) = match self.session_limit {
+            Some((limit, on_limit)) => (limit.to_string(), on_limit.as_arg()),
+            None => (String::new(), ""),
+        };
+        let reply: Vec<RedisValue> = match self.storage {
+            StorageMode::String => {
+                let expected = match expected_version {
+                    None => Bytes::from_static(b"-"),
+                    Some(0) => Bytes::new(),
+                    Some(version) => Bytes::copy_from_slice(&version::header(version)),
+                };
+                let value = self.encode_stored(session)?;
+                let args: Vec<RedisValue> = vec![
+                    layout.into(),
+                    expiration.into(),
+                    id.into(),
+                    now_millis.into(),
+                    limit.into(),
+                    on_limit.into(),
+                    expected.into(),
+                    self.tombstone_millis().unwrap_or_default().into(),
+                    value.into(),
+                ];
+                scripts::store_string()
+                    .evalsha_with_reload(self.pool.next(), keys, args)
+                    .await?
+            }
+            StorageMode::Hash => {
+                let mut args = vec![
+                    layout.to_string(),
+                    expiration.to_string(),
+                    id,
+                    now_millis.to_string(),
+                    limit,
+                    on_limit.to_string(),
+                    match expected_version {
+                        None => "-".to_string(),
+                        Some(0) => String::new(),
+                        Some(version) => version.to_string(),
+                    },
+                    self.tombstone_millis().unwrap_or_default(),
+                ];
+                for (field, value) in storage::to_fields(session)? {
+                    args.extend([field, value]);
+                }
+                scripts::store_hash()
+                    .evalsha_with_reload(self.pool.next(), keys, args)
+                    .await?
+            }
+        };
+
+        let result = reply.first().and_then(RedisValue::as_i64).unwrap_or(0);
+        if result == 1 {
+            for evicted in &reply[1..] {
+                if let Some(evicted) = evicted.as_str() {
+                    self.invalidate_cached(&evicted).await;
+                }
+            }
+        }
+        Ok(result)
+    }
 }
 
 #[async_trait]
 impl SessionStore for RedisSessionStore {
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
         let id = Session::id_from_cookie_value(&cookie_value)?;
-        Ok(self
-            .pool
-            .get::<Option<String>, String>(self.prefix_key(&id))
-            .await?
-            .map(|v| serde_json::from_str(&v))
-            .transpose()?)
+        let key = self.prefix_key(&id);
+        let now_millis = Utc::now().timestamp_millis();
+        let (cached, generation) = self.cached_session(&id).await?;
+        if let Some(mut session) = cached.filter(|session| !self.is_expired(session, now_millis)) {
+            self.touch_user_set(&session, now_millis).await?;
+            self.remember_loaded(&mut session)?;
+            return Ok(Some(session));
+        }
+
+        let mut session = match self.storage {
+            StorageMode::String => {
+                let Some(bytes) = self.get_value(&id, &key).await? else {
+                    self.report_tombstone(&id).await?;
+                    return Ok(None);
+                };
+                let session = self.decode(&id, &bytes)?;
+                if self.is_expired(&session, now_millis) {
+                    let user_id = meta::user_id(&session);
+                    let (keys, layout) = self.script_keys(&id, user_id.as_deref());
+                    let args: Vec<RedisValue> = vec![layout.into(), bytes.into(), id.into()];
+                    scripts::delete_if_unchanged()
+                        .evalsha_with_reload::<(), _, _>(self.pool.next(), keys, args)
+                        .await?;
+                    return Ok(None);
+                }
+                session
+            }
+            StorageMode::Hash => {
+                let fields = self.get_fields(&id, &key).await?;
+                let Some(session) = storage::from_fields(fields)? else {
+                    self.report_tombstone(&id).await?;
+                    return Ok(None);
+                };
+                if self.is_expired(&session, now_millis) {
+                    let expiry = session.expiry().map(|expiry| expiry.to_rfc3339());
+                    let user_id = meta::user_id(&session);
+                    let (keys, layout) = self.script_keys(&id, user_id.as_deref());
+                    let args = vec![
+                        layout,
+                        storage::EXPIRY_FIELD.to_string(),
+                        expiry.unwrap_or_default(),
+                        id,
+                    ];
+                    scripts::delete_hash_if_unchanged()
+                        .evalsha_with_reload::<(), _, _>(self.pool.next(), keys, args)
+                        .await?;
+                    return Ok(None);
+                }
+                session
+            }
+        };
+
+        if let Some(policy) = self.timeout_policy {
+            policy.touch(&mut session, now_millis);
+        }
+        self.touch_user_set(&session, now_millis).await?;
+        self.cache_session(&session, generation)?;
+        self.remember_loaded(&mut session)?;
+
+        Ok(Some(session))
     }
 
     async fn store_session(&self, session: Session) -> Result<Option<String>> {
-        let id = self.prefix_key(session.id());
-        let string = serde_json::to_string(&session)?;
-        let expiration = session
-            .expires_in()
-            .map(|d| Expiration::EX(d.as_secs() as i64));
+        let mut session_to_store = deep_copy(&session)?;
+        let data_changed = session.data_changed();
+        let mut cookie_value = session.into_cookie_value();
+        let session = &mut session_to_store;
+        let loaded = merge::take_loaded(session);
 
-        self.pool.set(id, string, expiration, None, false).await?;
+        let id = session.id().to_string();
+        let user_id = meta::user_id(session);
+        let (mut keys, layout) = self.script_keys(&id, user_id.as_deref());
+        let now_millis = Utc::now().timestamp_millis();
+        if let Some(policy) = self.timeout_policy {
+            policy.record(session, now_millis);
+        }
+
+        let expiration = match self.expires_at_millis(session, now_millis) {
+            Some(expires_at) if expires_at <= now_millis => {
+                scripts::delete_session()
+                    .evalsha_with_reload::<(), _, _>(self.pool.next(), keys, vec![&layout, &id])
+                    .await?;
+                self.invalidate_cached(&id).await;
+                return Ok(None);
+            }
+            expires_at => expires_at,
+        };
+        let expiration = expiration.map_or_else(String::new, |ms| ms.to_string());
+
+        if self.unchanged_sessions != UnchangedSessions::Write {
+            let expiry = meta::expiry(session);
+            let unchanged = cookie_value.is_none()
+                && !data_changed
+                && session.get_raw(meta::STORED_EXPIRY).as_ref() == Some(&expiry);
+
+            match self.unchanged_sessions {
+                UnchangedSessions::Skip if unchanged => return Ok(None),
+                UnchangedSessions::RefreshTtl if unchanged => {
+                    let exists: bool = scripts::refresh_ttl()
+                        .evalsha_with_reload(
+                            self.pool.next(),
+                            keys.clone(),
+                            vec![&layout, &expiration, &id],
+                        )
+                        .await?;
+                    if exists {
+                        return Ok(None);
+                    }
+                }
+                _ => {}
+            }
+
+            meta::set(session, meta::STORED_EXPIRY, expiry);
+        }
 
-        Ok(session.into_cookie_value())
+        let is_new = cookie_value.is_some();
+        let mut result = self
+            .write_session(
+                session,
+                keys.clone(),
+                &layout,
+                &expiration,
+                now_millis,
+                is_new,
+            )
+            .await?;
+        // a new session whose id is taken gets a new id
+        for _ in 1..ID_ATTEMPTS {
+            if result != -2 {
+                break;
+            }
+            let mut regenerated = session.clone();
+            regenerated.regenerate();
+            *session = regenerated.clone();
+            cookie_value = regenerated.into_cookie_value();
+            keys = self.script_keys(session.id(), user_id.as_deref()).0;
+            result = self
+                .write_session(
+                    session,
+                    keys.clone(),
+                    &layout,
+                    &expiration,
+                    now_millis,
+                    is_new,
+                )
+                .await?;
+        }
+        if result == -1 && !matches!(self.merge_strategy, MergeStrategy::Fail) {
+            result = self
+                .merge_session(session, loaded, keys, &layout, &expiration, now_millis)
+                .await?;
+        }
+
+        match (result, user_id) {
+            (-1, _) => Err(Error::VersionConflict(session.id().to_string()).into()),
+            (-2, _) => Err(Error::IdCollision(session.id().to_string()).into()),
+            (-3, _) => Err(Error::SessionDestroyed(session.id().to_string()).into()),
+            (0, Some(user_id)) => Err(Error::SessionLimitReached(user_id).into()),
+            _ => {
+                if !is_new {
+                    self.invalidate_cached(session.id()).await;
+                }
+                Ok(cookie_value)
+            }
+        }
     }
 
     async fn destroy_session(&self, session: Session) -> Result {
-        Ok(self.pool.del(self.prefix_key(session.id())).await?)
+        let user_id = meta::user_id(&session);
+        let (keys, layout) = self.script_keys(session.id(), user_id.as_deref());
+        let mut args = vec![layout, session.id().to_string()];
+        args.extend(self.tombstone_millis());
+        scripts::delete_session()
+            .evalsha_with_reload::<(), _, _>(self.pool.next(), keys, args)
+            .await?;
+        self.invalidate_cached(session.id()).await;
+        Ok(())
     }
 
     async fn clear_store(&self) -> Result {
-        match self.prefix {
-            None => Ok(self.pool.flushall(false).await?),
-            Some(_) => match self.ids().await? {
-                None => Ok(()),
-                Some(ids) => Ok(self.pool.del(ids).await?),
-            },
-        }
+        self.clear().await?;
+        Ok(())
+    }
+}
+
+/// how often a new or regenerated session is given a new id when its id is
+/// taken
+const ID_ATTEMPTS: usize = 3;
+
+/// the key of the session index, appended to the prefix
+const INDEX_KEY: &str = "__afs:index";
+/// the start of the keys of the user sets, followed by the user id
+const USER_KEY_PREFIX: &str = "__afs:user:";
+/// the start of the keys of the session locks, followed by the session id
+const LOCK_KEY_PREFIX: &str = "__afs:lock:";
+/// the start of the keys of the tombstones, followed by the session id
+const TOMBSTONE_KEY_PREFIX: &str = "__afs:tombstone:";
+
+fn earliest(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
     }
 }
 
+/// copies a session without sharing its data, which clones of a session do,
+/// so the store can change it without touching the caller's copies
+fn deep_copy(session: &Session) -> Result<Session> {
+    Ok(serde_json::from_value(serde_json::to_value(session)?)?)
+}
+
+/// the whole milliseconds in a duration, saturating at `i64::MAX`
+fn millis(duration: Duration) -> i64 {
+    duration.as_millis().min(i64::MAX as u128) as i64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_session::chrono::{self, TimeZone};
+    use futures::stream::TryStreamExt;
     use std::time::Duration;
     use tokio::time::sleep;
 
-    async fn create_session_store() -> RedisSessionStore {
+    fn create_pool() -> RedisPool {
         let conf = RedisConfig::from_url("redis://127.0.0.1:6379").unwrap();
-        let pool = RedisPool::new(conf, None, None, 6).unwrap();
+        RedisPool::new(conf, None, None, 6).unwrap()
+    }
+
+    async fn create_session_store() -> RedisSessionStore {
+        create_session_store_with(|builder| builder).await
+    }
 
+    async fn create_session_store_with(
+        configure: impl FnOnce(RedisSessionStoreBuilder) -> RedisSessionStoreBuilder,
+    ) -> RedisSessionStore {
+        let pool = create_pool();
         pool.connect();
         pool.wait_for_connect().await.unwrap();
 
-        let store = RedisSessionStore::from_pool(pool, Some("async-session-test/".into()));
+        let builder = RedisSessionStore::builder(pool).prefix("async-session-test/");
+        let store = configure(builder).build().unwrap();
         store.clear_store().await.unwrap();
         store
     }
@@ -198,6 +811,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::clone_on_copy)]
     async fn updating_a_session_extending_expiry() -> Result {
         let store = create_session_store().await;
         let mut session = Session::new();
@@ -282,4 +896,1143 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn building_a_store_with_invalid_options() {
+        let invalid = [
+            RedisSessionStore::builder(create_pool()).prefix(""),
+            RedisSessionStore::builder(create_pool()).default_ttl(Duration::ZERO),
+            RedisSessionStore::builder(create_pool()).max_ttl(Duration::from_micros(10)),
+            RedisSessionStore::builder(create_pool())
+                .default_ttl(Duration::from_secs(10))
+                .max_ttl(Duration::from_secs(5)),
+            RedisSessionStore::builder(create_pool()).scan_count(0),
+            RedisSessionStore::builder(create_pool()).clear_batch_size(0),
+            RedisSessionStore::builder(create_pool()).session_limit(0, SessionLimitPolicy::Reject),
+            RedisSessionStore::builder(create_pool()).merge_strategy(MergeStrategy::KeepExisting),
+            RedisSessionStore::builder(create_pool()).tombstones(Duration::ZERO),
+            RedisSessionStore::builder(create_pool()).tombstone_audit(|_| {}),
+            RedisSessionStore::builder(create_pool()).allow_unencrypted(true),
+            RedisSessionStore::builder(create_pool()).cache(0, Duration::from_secs(1)),
+            RedisSessionStore::builder(create_pool())
+                .cache(100, Duration::from_secs(1))
+                .sliding_expiration(Duration::from_secs(10)),
+            RedisSessionStore::builder(create_pool()).sliding_expiration(Duration::ZERO),
+            RedisSessionStore::builder(create_pool())
+                .sliding_expiration(Duration::from_secs(10))
+                .max_ttl(Duration::from_secs(5)),
+            RedisSessionStore::builder(create_pool())
+                .timeout_policy(TimeoutPolicy::new().absolute(Duration::ZERO)),
+        ];
+
+        for builder in invalid {
+            assert!(matches!(builder.build(), Err(Error::InvalidConfig(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn storing_a_session_with_default_ttl() -> Result {
+        let store =
+            create_session_store_with(|builder| builder.default_ttl(Duration::from_secs(5))).await;
+        let session = Session::new();
+        let cloned = session.clone();
+        store.store_session(session).await?;
+
+        let ttl = store.ttl_for_session(&cloned).await?;
+        assert!(ttl > 3 && ttl <= 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storing_a_session_with_max_ttl() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder
+                .default_ttl(Duration::from_secs(5))
+                .max_ttl(Duration::from_secs(10))
+        })
+        .await;
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        let cloned = session.clone();
+        store.store_session(session).await?;
+
+        let ttl = store.ttl_for_session(&cloned).await?;
+        assert!(ttl > 8 && ttl <= 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn computing_expiry_in_milliseconds() {
+        let now = Utc::now().timestamp_millis();
+        let store = RedisSessionStore::builder(create_pool()).build().unwrap();
+
+        assert_eq!(None, store.expires_at_millis(&Session::new(), now));
+
+        let mut session = Session::new();
+        session.set_expiry(Utc.timestamp_millis_opt(now + 900).unwrap());
+        assert_eq!(Some(now + 900), store.expires_at_millis(&session, now));
+
+        session.set_expiry(Utc.timestamp_millis_opt(now + 1).unwrap());
+        assert_eq!(Some(now + 1), store.expires_at_millis(&session, now));
+
+        session.set_expiry(Utc.timestamp_millis_opt(now).unwrap());
+        assert_eq!(Some(now), store.expires_at_millis(&session, now));
+
+        let store = RedisSessionStore::builder(create_pool())
+            .default_ttl(Duration::from_millis(1500))
+            .max_ttl(Duration::from_millis(2500))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            Some(now + 1500),
+            store.expires_at_millis(&Session::new(), now)
+        );
+
+        session.set_expiry(Utc.timestamp_millis_opt(now + 60_000).unwrap());
+        assert_eq!(Some(now + 2500), store.expires_at_millis(&session, now));
+
+        let store = RedisSessionStore::builder(create_pool())
+            .default_ttl(Duration::from_millis(1500))
+            .sliding_expiration(Duration::from_millis(1000))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            Some(now + 1000),
+            store.expires_at_millis(&Session::new(), now)
+        );
+        assert_eq!(Some(now + 1000), store.expires_at_millis(&session, now));
+        session.set_expiry(Utc.timestamp_millis_opt(now + 500).unwrap());
+        assert_eq!(Some(now + 500), store.expires_at_millis(&session, now));
+
+        // durations beyond i64 milliseconds saturate instead of wrapping
+        let store = RedisSessionStore::builder(create_pool())
+            .default_ttl(Duration::MAX)
+            .build()
+            .unwrap();
+        assert_eq!(
+            Some(i64::MAX),
+            store.expires_at_millis(&Session::new(), now)
+        );
+    }
+
+    #[tokio::test]
+    async fn storing_a_session_with_less_than_a_second_left() -> Result {
+        let store = create_session_store().await;
+        let mut session = Session::new();
+        session.expire_in(Duration::from_millis(900));
+        let cloned = session.clone();
+
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let pttl = store.pttl_for_session(&cloned).await?;
+        assert!(pttl > 0 && pttl <= 900);
+        assert!(store.load_session(cookie_value.clone()).await?.is_some());
+
+        sleep(Duration::from_millis(1000)).await;
+        assert_eq!(None, store.load_session(cookie_value).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storing_an_expired_session_deletes_it() -> Result {
+        let store = create_session_store().await;
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.set_expiry(Utc::now() - chrono::Duration::milliseconds(1));
+
+        assert_eq!(None, store.store_session(session).await?);
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        assert_eq!(0, store.count().await?);
+
+        Ok(())
+    }
+
+    async fn write_raw_session(store: &RedisSessionStore, session: &Session) -> Result {
+        let id = store.prefix_key(session.id());
+        if store.storage == StorageMode::Hash {
+            store.pool.del::<(), _>(&id).await?;
+            let fields: HashMap<String, String> =
+                storage::to_fields(session)?.into_iter().collect();
+            return Ok(store.pool.hset(id, fields).await?);
+        }
+        let bytes = store.encode(session)?;
+        Ok(store.pool.set(id, bytes, None, None, false).await?)
+    }
+
+    #[tokio::test]
+    async fn loading_an_expired_session_purges_it() -> Result {
+        let store = create_session_store().await;
+        let mut session = Session::new();
+        session.set_expiry(Utc::now() - chrono::Duration::seconds(1));
+        write_raw_session(&store, &session).await?;
+
+        let cookie_value = session.into_cookie_value().unwrap();
+        assert_eq!(1, store.count().await?);
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        assert_eq!(0, store.count().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loading_an_expired_session_within_clock_skew_tolerance() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder.clock_skew_tolerance(Duration::from_secs(5))
+        })
+        .await;
+
+        let mut session = Session::new();
+        session.set_expiry(Utc::now() - chrono::Duration::seconds(1));
+        write_raw_session(&store, &session).await?;
+        let cookie_value = session.into_cookie_value().unwrap();
+        assert!(store.load_session(cookie_value).await?.is_some());
+
+        let mut session = Session::new();
+        session.set_expiry(Utc::now() - chrono::Duration::seconds(10));
+        write_raw_session(&store, &session).await?;
+        let cookie_value = session.into_cookie_value().unwrap();
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        assert_eq!(1, store.count().await?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn storing_a_session_with_another_codec() -> Result {
+        let store = create_session_store_with(|builder| builder.codec(MessagePackCodec)).await;
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(&session.get::<String>("key").unwrap(), "value");
+
+        let bytes: Bytes = store.pool.get(store.prefix_key(session.id())).await?;
+        assert_eq!(session.id(), MessagePackCodec.decode(&bytes)?.id());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn storing_compressed_and_uncompressed_sessions() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder
+                .compression(Compression::Zstd { level: 3 })
+                .compression_threshold(256)
+        })
+        .await;
+
+        let mut small = Session::new();
+        small.insert("key", "value")?;
+        let mut large = Session::new();
+        large.insert("key", "value".repeat(100))?;
+
+        let (small_id, large_id) = (small.id().to_string(), large.id().to_string());
+        let small_cookie = store.store_session(small).await?.unwrap();
+        let large_cookie = store.store_session(large).await?.unwrap();
+
+        let small_bytes: Bytes = store.pool.get(store.prefix_key(&small_id)).await?;
+        let large_bytes: Bytes = store.pool.get(store.prefix_key(&large_id)).await?;
+        assert_eq!(Some(&b'{'), small_bytes.first());
+        assert_eq!(Some(&0x01), large_bytes.first());
+
+        let small = store.load_session(small_cookie).await?.unwrap();
+        let large = store.load_session(large_cookie).await?.unwrap();
+        assert_eq!(&small.get::<String>("key").unwrap(), "value");
+        assert_eq!(large.get::<String>("key").unwrap(), "value".repeat(100));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[tokio::test]
+    async fn storing_encrypted_sessions_and_rotating_keys() -> Result {
+        let old_keyring = Keyring::new().key(1, EncryptionKey::Aes256Gcm([1; 32]));
+        let store =
+            create_session_store_with(|builder| builder.encryption(old_keyring.clone())).await;
+
+        let mut session = Session::new();
+        session.insert("email", "user@example.com")?;
+        let id = session.id().to_string();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let bytes: Bytes = store.pool.get(store.prefix_key(&id)).await?;
+        assert_eq!(&[0x08, 0, 0, 0, 1], &bytes[..5]);
+        assert!(!bytes.windows(16).any(|w| w == b"user@example.com"));
+
+        let plain = RedisSessionStore {
+            keyring: None,
+            ..store.clone()
+        };
+        let mut unencrypted = Session::new();
+        unencrypted.insert("email", "user@example.com")?;
+        write_raw_session(&plain, &unencrypted).await?;
+        let unencrypted = unencrypted.into_cookie_value().unwrap();
+        assert!(store.load_session(unencrypted.clone()).await.is_err());
+
+        let keyring = old_keyring.key(2, EncryptionKey::Aes256Gcm([2; 32]));
+        let store = RedisSessionStore {
+            keyring: Some(Arc::new(keyring)),
+            allow_unencrypted: true,
+            ..store
+        };
+        assert!(store.load_session(unencrypted).await?.is_some());
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(&session.get::<String>("email").unwrap(), "user@example.com");
+        store.store_session(session).await?;
+
+        let bytes: Bytes = store.pool.get(store.prefix_key(&id)).await?;
+        assert_eq!(&[0x08, 0, 0, 0, 2], &bytes[..5]);
+        assert!(store.load_session(cookie_value).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storing_sessions_as_hashes() -> Result {
+        let store =
+            create_session_store_with(|builder| builder.storage_mode(StorageMode::Hash)).await;
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.insert("count", 1)?;
+        session.expire_in(Duration::from_secs(5));
+        let id = session.id().to_string();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let fields: HashMap<String, String> = store.pool.hgetall(store.prefix_key(&id)).await?;
+        assert_eq!(Some(&r#""value""#.to_string()), fields.get("key"));
+        assert!(store.pool.pttl::<i64, _>(store.prefix_key(&id)).await? > 0);
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(&id, session.id());
+        assert_eq!(&session.get::<String>("key").unwrap(), "value");
+        assert!(session.expiry().is_some());
+        assert_eq!(1, store.count().await?);
+
+        let mut session = session;
+        session.remove("count");
+        store.store_session(session).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(None, session.get::<i32>("count"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reading_and_updating_single_fields() -> Result {
+        let store =
+            create_session_store_with(|builder| builder.storage_mode(StorageMode::Hash)).await;
+
+        let mut session = Session::new();
+        session.insert("count", 1)?;
+        let id = session.id().to_string();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        assert_eq!(Some(1), store.get_field::<i32>(&id, "count").await?);
+        assert_eq!(None, store.get_field::<i32>(&id, "missing").await?);
+
+        assert!(store.set_field(&id, "count", 2).await?);
+        assert!(store.set_field(&id, "name", "user").await?);
+        assert!(store.remove_field(&id, "count").await?);
+        assert!(!store.remove_field(&id, "count").await?);
+        assert!(!store.set_field("missing", "count", 1).await?);
+        assert!(store.set_field(&id, "__afs:id", "other").await.is_err());
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(None, session.get::<i32>("count"));
+        assert_eq!(&session.get::<String>("name").unwrap(), "user");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn field_access_requires_hash_storage() {
+        let store = RedisSessionStore::builder(create_pool()).build().unwrap();
+        let error = store.get_field::<i32>("id", "count").await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+
+        let hash = |builder: RedisSessionStoreBuilder| builder.storage_mode(StorageMode::Hash);
+        assert!(hash(RedisSessionStore::builder(create_pool()))
+            .codec(JsonCodec)
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn skipping_unchanged_sessions() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder.unchanged_sessions(UnchangedSessions::Skip)
+        })
+        .await;
+
+        let mut session = Session::new();
+        session.insert("count", 1)?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        let key = store.prefix_key(session.id());
+
+        store.pool.del::<(), _>(&key).await?;
+        assert_eq!(None, store.store_session(session.clone()).await?);
+        assert_eq!(0, store.count().await?);
+
+        let mut changed = session.clone();
+        changed.insert("count", 2)?;
+        store.store_session(changed).await?;
+        assert_eq!(1, store.count().await?);
+
+        let mut expiring = store.load_session(cookie_value).await?.unwrap();
+        expiring.expire_in(Duration::from_secs(5));
+        store.store_session(expiring.clone()).await?;
+        assert!(store.pool.pttl::<i64, _>(&key).await? > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refreshing_the_ttl_of_unchanged_sessions() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder
+                .default_ttl(Duration::from_secs(60))
+                .unchanged_sessions(UnchangedSessions::RefreshTtl)
+        })
+        .await;
+
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        let key = store.prefix_key(session.id());
+
+        store.pool.expire::<(), _>(&key, 2).await?;
+        let value: Bytes = store.pool.get(&key).await?;
+        assert_eq!(None, store.store_session(session.clone()).await?);
+        assert!(store.pool.pttl::<i64, _>(&key).await? > 59_000);
+        assert_eq!(value, store.pool.get::<Bytes, _>(&key).await?);
+
+        store.pool.del::<(), _>(&key).await?;
+        store.store_session(session).await?;
+        assert_eq!(1, store.count().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loading_a_session_slides_its_expiry() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder
+                    .storage_mode(storage)
+                    .sliding_expiration(Duration::from_secs(60))
+            })
+            .await;
+
+            let session = Session::new();
+            let cloned = session.clone();
+            let cookie_value = store.store_session(session).await?.unwrap();
+            assert!(store.ttl_for_session(&cloned).await? > 55);
+
+            let key = store.prefix_key(cloned.id());
+            store.pool.expire::<(), _>(&key, 5).await?;
+            assert!(store.load_session(cookie_value).await?.is_some());
+            assert!(store.ttl_for_session(&cloned).await? > 55);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enforcing_idle_and_absolute_timeouts() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder.storage_mode(storage).timeout_policy(
+                    TimeoutPolicy::new()
+                        .idle(Duration::from_secs(10))
+                        .absolute(Duration::from_secs(60)),
+                )
+            })
+            .await;
+
+            let session = Session::new();
+            let cloned = session.clone();
+            let cookie_value = store.store_session(session).await?.unwrap();
+            let ttl = store.ttl_for_session(&cloned).await?;
+            assert!(ttl > 8 && ttl <= 10);
+
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            assert!(session.data_changed());
+
+            let now = Utc::now().timestamp_millis();
+            session.insert_raw(meta::CREATED_AT, (now - 120_000).to_string());
+            write_raw_session(&store, &session).await?;
+            assert_eq!(None, store.load_session(cookie_value.clone()).await?);
+            assert_eq!(0, store.count().await?);
+
+            session.insert_raw(meta::CREATED_AT, now.to_string());
+            session.insert_raw(meta::LAST_ACCESS, (now - 20_000).to_string());
+            write_raw_session(&store, &session).await?;
+            assert_eq!(None, store.load_session(cookie_value).await?);
+            assert_eq!(0, store.count().await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn counting_sessions_with_an_index() -> Result {
+        let store = create_session_store_with(|builder| builder.session_index(true)).await;
+        let index_key = store.index_key.clone().unwrap();
+
+        let session = Session::new();
+        let cloned = session.clone();
+        store.store_session(session).await?;
+        let mut expiring = Session::new();
+        expiring.expire_in(Duration::from_millis(200));
+        store.store_session(expiring).await?;
+        store.store_session(Session::new()).await?;
+        assert_eq!(3, store.count().await?);
+
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(2, store.count().await?);
+        assert_eq!(2, store.pool.zcard::<usize, _>(&index_key).await?);
+
+        store.destroy_session(cloned).await?;
+        assert_eq!(1, store.count().await?);
+        assert_eq!(1, store.session_ids().count().await);
+
+        store.clear_store().await?;
+        assert_eq!(0, store.count().await?);
+        assert!(!store.pool.exists::<bool, _>(&index_key).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn counting_and_clearing_without_a_prefix() -> Result {
+        let pool = create_pool();
+        pool.connect();
+        pool.wait_for_connect().await?;
+        let store = RedisSessionStore::from_pool(pool, None);
+        store.clear_store().await?;
+
+        let other_key = "async-session-test/not-a-session";
+        store
+            .pool
+            .set::<(), _, _>(other_key, "value", None, None, false)
+            .await?;
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        assert_eq!(1, store.count().await?);
+
+        store.clear_store().await?;
+        assert_eq!(0, store.count().await?);
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert!(store.pool.exists::<bool, _>(other_key).await?);
+
+        store.pool.del::<(), _>(other_key).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clearing_the_store_in_batches() -> Result {
+        for session_index in [false, true] {
+            let store = create_session_store_with(|builder| {
+                builder.session_index(session_index).clear_batch_size(2)
+            })
+            .await;
+
+            for _ in 0..5 {
+                store.store_session(Session::new()).await?;
+            }
+            let lock = store.lock("locked", Duration::from_secs(5)).await?;
+
+            let mut reported = Vec::new();
+            let summary = store
+                .clear_with_progress(|progress| reported.push(progress.deleted))
+                .await?;
+            assert_eq!(5, summary.deleted);
+            assert_eq!(3, summary.batches);
+            assert_eq!(vec![2, 4, 5], reported);
+            assert_eq!(0, store.count().await?);
+            assert!(lock.release().await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streaming_sessions() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store =
+                create_session_store_with(|builder| builder.storage_mode(storage).scan_count(2))
+                    .await;
+
+            let mut ids = Vec::new();
+            for count in 0..5 {
+                let mut session = Session::new();
+                session.insert("count", count)?;
+                ids.push(session.id().to_string());
+                store.store_session(session).await?;
+            }
+            let mut expired = Session::new();
+            expired.set_expiry(Utc::now() - chrono::Duration::seconds(1));
+            write_raw_session(&store, &expired).await?;
+
+            let mut streamed: Vec<String> = store.session_ids().try_collect().await?;
+            streamed.sort();
+            streamed.dedup();
+            assert_eq!(6, streamed.len());
+
+            let sessions: Vec<Session> = store.sessions().try_collect().await?;
+            let mut streamed: Vec<String> = sessions.iter().map(|s| s.id().to_string()).collect();
+            streamed.sort();
+            streamed.dedup();
+            ids.sort();
+            assert_eq!(ids, streamed);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paging_through_sessions() -> Result {
+        for session_index in [false, true] {
+            let store =
+                create_session_store_with(|builder| builder.session_index(session_index)).await;
+
+            let mut ids = Vec::new();
+            for _ in 0..7 {
+                let session = Session::new();
+                ids.push(session.id().to_string());
+                store.store_session(session).await?;
+            }
+
+            let mut listed = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = store.list_sessions(cursor.as_deref(), 3).await?;
+                listed.extend(page.items.iter().map(|s| s.id().to_string()));
+                match page.next_cursor {
+                    Some(next) => {
+                        assert!(page.items.len() >= 3);
+                        cursor = Some(next);
+                    }
+                    None => break,
+                }
+            }
+
+            ids.sort();
+            listed.sort();
+            listed.dedup();
+            assert_eq!(ids, listed);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listing_and_destroying_the_sessions_of_a_user() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder.storage_mode(storage).session_index(true)
+            })
+            .await;
+
+            let mut cookie_values = Vec::new();
+            for user_id in ["alice", "alice", "alice", "bob"] {
+                let mut session = Session::new();
+                RedisSessionStore::bind_user(&mut session, user_id)?;
+                cookie_values.push(store.store_session(session).await?.unwrap());
+            }
+
+            let first = store.load_session(cookie_values[0].clone()).await?.unwrap();
+            assert_eq!(Some("alice".into()), RedisSessionStore::user_id(&first));
+            let ids = store.user_session_ids("alice").await?;
+            assert_eq!(3, ids.len());
+            assert!(ids.contains(&first.id().to_string()));
+
+            store.destroy_session(first).await?;
+            let second = store.load_session(cookie_values[1].clone()).await?.unwrap();
+            store
+                .pool
+                .del::<(), _>(store.prefix_key(second.id()))
+                .await?;
+            assert_eq!(1, store.user_session_ids("alice").await?.len());
+
+            assert_eq!(1, store.destroy_user_sessions("alice").await?);
+            assert!(store
+                .load_session(cookie_values[2].clone())
+                .await?
+                .is_none());
+            assert!(store.user_session_ids("alice").await?.is_empty());
+            assert_eq!(1, store.user_session_ids("bob").await?.len());
+            assert_eq!(1, store.count().await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expiring_user_sets_with_their_sessions() -> Result {
+        let store = create_session_store().await;
+        let user_key = store.user_key("alice");
+        let for_alice = |expiry: Option<u64>| {
+            let mut session = Session::new();
+            if let Some(secs) = expiry {
+                session.expire_in(Duration::from_secs(secs));
+            }
+            RedisSessionStore::bind_user(&mut session, "alice").map(|_| session)
+        };
+
+        store.store_session(for_alice(Some(10))?).await?;
+        let pttl: i64 = store.pool.pttl(&user_key).await?;
+        assert!(pttl > 0 && pttl <= 10_000);
+        store.store_session(for_alice(Some(20))?).await?;
+        let pttl: i64 = store.pool.pttl(&user_key).await?;
+        assert!(pttl > 10_000 && pttl <= 20_000);
+        store.store_session(for_alice(Some(5))?).await?;
+        let pttl: i64 = store.pool.pttl(&user_key).await?;
+        assert!(pttl > 10_000);
+
+        store.store_session(for_alice(None)?).await?;
+        store.store_session(for_alice(Some(5))?).await?;
+        assert_eq!(-1, store.pool.pttl::<i64, _>(&user_key).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_the_sessions_of_a_user() -> Result {
+        async fn login(store: &RedisSessionStore) -> Result<String> {
+            let mut session = Session::new();
+            RedisSessionStore::bind_user(&mut session, "alice")?;
+            Ok(store.store_session(session).await?.unwrap())
+        }
+
+        let store = create_session_store_with(|builder| {
+            builder.session_limit(2, SessionLimitPolicy::Reject)
+        })
+        .await;
+        let first = login(&store).await?;
+        login(&store).await?;
+        let error = login(&store).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::SessionLimitReached(user_id)) if user_id == "alice"
+        ));
+
+        let mut session = store.load_session(first).await?.unwrap();
+        session.insert("key", "value")?;
+        store.store_session(session).await?;
+        assert_eq!(2, store.user_session_ids("alice").await?.len());
+
+        for policy in [
+            SessionLimitPolicy::EvictOldest,
+            SessionLimitPolicy::EvictLeastRecentlyUsed,
+        ] {
+            let store = create_session_store_with(|builder| {
+                builder
+                    .session_limit(2, policy)
+                    .cache(100, Duration::from_secs(60))
+            })
+            .await;
+            let first = login(&store).await?;
+            sleep(Duration::from_millis(5)).await;
+            let second = login(&store).await?;
+            sleep(Duration::from_millis(5)).await;
+
+            store.load_session(second.clone()).await?.unwrap();
+            sleep(Duration::from_millis(5)).await;
+            let session = store.load_session(first.clone()).await?.unwrap();
+            store.store_session(session).await?;
+            store.load_session(first.clone()).await?.unwrap();
+            sleep(Duration::from_millis(5)).await;
+            login(&store).await?;
+
+            assert_eq!(2, store.user_session_ids("alice").await?.len());
+            let evicted = match policy {
+                SessionLimitPolicy::EvictOldest => first,
+                _ => second,
+            };
+            assert!(store.load_session(evicted).await?.is_none());
+        }
+
+        // loading a session without storing it counts as using it
+        let store = create_session_store_with(|builder| {
+            builder.session_limit(2, SessionLimitPolicy::EvictLeastRecentlyUsed)
+        })
+        .await;
+        let first = login(&store).await?;
+        sleep(Duration::from_millis(5)).await;
+        let second = login(&store).await?;
+        sleep(Duration::from_millis(5)).await;
+        store.load_session(first.clone()).await?.unwrap();
+        sleep(Duration::from_millis(5)).await;
+        login(&store).await?;
+        assert!(store.load_session(first).await?.is_some());
+        assert!(store.load_session(second).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detecting_concurrent_changes_with_versioned_sessions() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder.storage_mode(storage).versioned_sessions(true)
+            })
+            .await;
+            let mut session = Session::new();
+            session.insert("count", 0)?;
+            let cookie_value = store.store_session(session).await?.unwrap();
+
+            let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+            let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+            first.insert("count", 1)?;
+            second.insert("count", 2)?;
+            store.store_session(first).await?;
+            let kept = second.clone();
+            let error = store.store_session(second).await.unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<Error>(),
+                Some(Error::VersionConflict(_))
+            ));
+            // a failed store leaves the caller's copies as they were
+            assert!(store.store_session(kept).await.is_err());
+
+            let updated = store
+                .update_session(&cookie_value, 3, |session| {
+                    let count: usize = session.get("count").unwrap_or_default();
+                    Ok(session.insert("count", count + 1)?)
+                })
+                .await?;
+            assert!(updated);
+
+            let session = store.load_session(cookie_value).await?.unwrap();
+            assert_eq!(Some(2), session.get::<usize>("count"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detecting_concurrent_field_changes_with_versioned_sessions() -> Result {
+        let store = create_session_store_with(|builder| {
+            builder
+                .storage_mode(StorageMode::Hash)
+                .versioned_sessions(true)
+        })
+        .await;
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+
+        for change in ["set", "remove"] {
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            match change {
+                "set" => assert!(store.set_field(session.id(), "key", "value").await?),
+                _ => assert!(store.remove_field(session.id(), "key").await?),
+            }
+            session.insert("other", "value")?;
+            let error = store.store_session(session).await.unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<Error>(),
+                Some(Error::VersionConflict(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merging_concurrent_changes() -> Result {
+        let merge_carts = MergeStrategy::custom(|stored, incoming| {
+            let mut cart: Vec<String> = stored.get("cart").unwrap_or_default();
+            cart.extend(incoming.get::<Vec<String>>("cart").unwrap_or_default());
+            cart.dedup();
+            Ok(incoming.insert("cart", cart)?)
+        });
+
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder
+                    .storage_mode(storage)
+                    .versioned_sessions(true)
+                    .merge_strategy(merge_carts.clone())
+            })
+            .await;
+            let mut session = Session::new();
+            session.insert("cart", Vec::<String>::new())?;
+            let cookie_value = store.store_session(session).await?.unwrap();
+
+            let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+            let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+            first.insert("cart", vec!["apple"])?;
+            second.insert("cart", vec!["pear"])?;
+            second.insert("theme", "dark")?;
+            store.store_session(first).await?;
+            store.store_session(second).await?;
+
+            let session = store.load_session(cookie_value.clone()).await?.unwrap();
+            assert_eq!(
+                Some(vec!["apple".to_string(), "pear".to_string()]),
+                session.get("cart")
+            );
+            assert_eq!(Some("dark".to_string()), session.get("theme"));
+            assert_eq!(Some(3), meta::version(&session));
+
+            let store = RedisSessionStore {
+                merge_strategy: MergeStrategy::LastWriterWinsPerKey,
+                ..store
+            };
+            let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+            let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+            first.insert("theme", "light")?;
+            second.insert("cart", Vec::<String>::new())?;
+            store.store_session(first).await?;
+            store.store_session(second).await?;
+
+            let session = store.load_session(cookie_value).await?.unwrap();
+            assert_eq!(Some(Vec::<String>::new()), session.get("cart"));
+            assert_eq!(Some("light".to_string()), session.get("theme"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn locking_a_session() -> Result {
+        let store = create_session_store().await;
+        let ttl = Duration::from_secs(5);
+        let lock = store.lock("session", ttl).await?;
+
+        let error = store.lock("session", ttl).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::SessionLocked(id)) if id == "session"
+        ));
+        let error = store
+            .lock_with_timeout("session", ttl, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::SessionLocked(_))
+        ));
+        let other = store.lock("other session", ttl).await?;
+        assert!(other.release().await?);
+
+        assert!(lock.extend(Duration::from_millis(100)).await?);
+        let lock = store
+            .lock_with_timeout("session", ttl, Duration::from_secs(1))
+            .await?;
+        assert!(lock.release().await?);
+        assert_eq!(0, store.count().await?);
+
+        let lock = store.lock("session", Duration::from_millis(10)).await?;
+        sleep(Duration::from_millis(20)).await;
+        assert!(!lock.extend(ttl).await?);
+        assert!(!lock.release().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn regenerating_a_session() -> Result {
+        for storage in [StorageMode::String, StorageMode::Hash] {
+            let store = create_session_store_with(|builder| {
+                builder.storage_mode(storage).session_index(true)
+            })
+            .await;
+            let mut session = Session::new();
+            session.insert("key", "value")?;
+            session.expire_in(Duration::from_secs(10));
+            RedisSessionStore::bind_user(&mut session, "alice")?;
+            let cookie_value = store.store_session(session).await?.unwrap();
+
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            let old_id = session.id().to_string();
+            session.insert("logged in", true)?;
+            let new_cookie_value = store.regenerate(session).await?.unwrap();
+
+            assert!(store.load_session(cookie_value).await?.is_none());
+            let session = store.load_session(new_cookie_value).await?.unwrap();
+            assert_ne!(old_id, session.id());
+            assert_eq!(Some("value".to_string()), session.get("key"));
+            assert_eq!(Some(true), session.get("logged in"));
+            assert!(store.ttl_for_session(&session).await? > 0);
+            assert_eq!(1, store.count().await?);
+            assert_eq!(
+                vec![session.id().to_string()],
+                store.user_session_ids("alice").await?
+            );
+
+            store.destroy_session(session.clone()).await?;
+            assert!(store.regenerate(session).await?.is_none());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storing_a_new_session_with_a_taken_id() -> Result {
+        let store = create_session_store().await;
+        let mut session = Session::new();
+        session.insert("key", "existing")?;
+        write_raw_session(&store, &session).await?;
+
+        let id = session.id().to_string();
+        session.insert("key", "new")?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let stored = store.load_session(cookie_value).await?.unwrap();
+        assert_ne!(id, stored.id());
+        assert_eq!(Some("new".to_string()), stored.get("key"));
+
+        let existing = store.fetch_sessions(vec![id]).await?.pop().unwrap()?;
+        assert_eq!(Some("existing".to_string()), existing.get("key"));
+        assert_eq!(2, store.count().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaving_tombstones_for_destroyed_sessions() -> Result {
+        let audited = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let store = create_session_store_with(|builder| {
+            let audited = audited.clone();
+            builder
+                .tombstones(Duration::from_secs(5))
+                .tombstone_audit(move |id| audited.lock().unwrap().push(id.to_string()))
+        })
+        .await;
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session.clone()).await?;
+
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert_eq!(vec![session.id().to_string()], *audited.lock().unwrap());
+        let error = store.store_session(session).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::SessionDestroyed(_))
+        ));
+        assert_eq!(0, store.count().await?);
+
+        let mut session = Session::new();
+        RedisSessionStore::bind_user(&mut session, "alice")?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        store.destroy_user_sessions("alice").await?;
+        assert!(store.store_session(session).await.is_err());
+
+        let store = create_session_store_with(|builder| {
+            builder
+                .tombstones(Duration::from_secs(5))
+                .session_limit(1, SessionLimitPolicy::EvictOldest)
+        })
+        .await;
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let mut session = Session::new();
+            RedisSessionStore::bind_user(&mut session, "alice")?;
+            let cookie_value = store.store_session(session).await?.unwrap();
+            sessions.extend(store.load_session(cookie_value).await?);
+            sleep(Duration::from_millis(5)).await;
+        }
+        let evicted = sessions.remove(0);
+        let error = store.store_session(evicted).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::SessionDestroyed(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streaming_session_events() -> Result {
+        let store = create_session_store_with(|builder| builder.keyspace_notifications(true)).await;
+        let events = store.session_events().await?;
+        let mut events = Box::pin(events);
+
+        let unprefixed = RedisSessionStore::from_pool(store.pool.clone(), None);
+        let error = unprefixed.session_events().await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+        store
+            .pool
+            .set::<(), _, _>("unrelated", "value", None, None, false)
+            .await?;
+        store.pool.del::<(), _>("unrelated").await?;
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_millis(50));
+        let expiring_id = session.id().to_string();
+        let expiring = store.store_session(session).await?.unwrap();
+
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        store.destroy_session(session.clone()).await?;
+        assert_eq!(
+            Some(SessionEvent {
+                id: session.id().to_string(),
+                kind: SessionEventKind::Deleted,
+            }),
+            events.next().await
+        );
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(store.load_session(expiring).await?.is_none());
+        assert_eq!(
+            Some(SessionEvent {
+                id: expiring_id,
+                kind: SessionEventKind::Expired,
+            }),
+            events.next().await
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caching_sessions_across_stores() -> Result {
+        let ttl = Duration::from_secs(60);
+        let first = create_session_store_with(|builder| builder.cache(100, ttl)).await;
+        let second = create_session_store_with(|builder| builder.cache(100, ttl)).await;
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = first.store_session(session).await?.unwrap();
+        let mut session = first.load_session(cookie_value.clone()).await?.unwrap();
+        second.load_session(cookie_value.clone()).await?.unwrap();
+
+        session.insert("key", "changed")?;
+        first.store_session(session).await?;
+        sleep(Duration::from_millis(100)).await;
+        let session = second.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(Some("changed".to_string()), session.get("key"));
+
+        second.destroy_session(session).await?;
+        sleep(Duration::from_millis(100)).await;
+        assert!(first.load_session(cookie_value).await?.is_none());
+
+        let mut session = Session::new();
+        RedisSessionStore::bind_user(&mut session, "alice")?;
+        let cookie_value = first.store_session(session).await?.unwrap();
+        second.load_session(cookie_value.clone()).await?.unwrap();
+        first.destroy_user_sessions("alice").await?;
+        sleep(Duration::from_millis(100)).await;
+        assert!(second.load_session(cookie_value).await?.is_none());
+
+        Ok(())
+    }
 }
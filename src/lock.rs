@@ -0,0 +1,116 @@
+//! locks serializing the requests that touch one session
+
+use std::time::{Duration, Instant};
+
+use async_session::Result;
+use fred::{
+    interfaces::KeysInterface,
+    pool::RedisPool,
+    types::{Expiration, SetOptions},
+};
+
+use crate::{scripts, Error, RedisSessionStore, LOCK_KEY_PREFIX};
+
+/// the first delay between attempts to acquire a lock
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+/// the longest delay between attempts to acquire a lock
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// a lock on a session, held until it is [released](Self::release) or its
+/// ttl runs out. dropping the guard does not release the lock, it is only
+/// freed once the ttl runs out
+#[derive(Debug)]
+#[must_use = "the lock is held until it is released or its ttl runs out"]
+pub struct SessionLock {
+    pool: RedisPool,
+    key: String,
+    token: String,
+}
+
+impl SessionLock {
+    /// resets the ttl of the lock. returns `false` if the lock was lost
+    /// because its ttl ran out
+    pub async fn extend(&self, ttl: Duration) -> Result<bool> {
+        Ok(scripts::extend_lock()
+            .evalsha_with_reload(
+                self.pool.next(),
+                vec![self.key.as_str()],
+                vec![self.token.clone(), millis(ttl).to_string()],
+            )
+            .await?)
+    }
+
+    /// releases the lock. returns `false` if the lock was already lost
+    /// because its ttl ran out
+    pub async fn release(self) -> Result<bool> {
+        Ok(scripts::release_lock()
+            .evalsha_with_reload(self.pool.next(), vec![self.key], vec![self.token])
+            .await?)
+    }
+}
+
+impl RedisSessionStore {
+    /// locks the session with the given id for at most `ttl`, failing with
+    /// [`Error::SessionLocked`] if it is already locked. locks live next to
+    /// the sessions under the store prefix and work whether the session
+    /// exists or not
+    pub async fn lock(&self, session_id: &str, ttl: Duration) -> Result<SessionLock> {
+        let key = self.lock_key(session_id);
+        let token = format!("{:032x}", rand::random::<u128>());
+        let acquired: Option<String> = self
+            .pool
+            .set(
+                &key,
+                token.as_str(),
+                Some(Expiration::PX(millis(ttl))),
+                Some(SetOptions::NX),
+                false,
+            )
+            .await?;
+
+        match acquired {
+            Some(_) => Ok(SessionLock {
+                pool: self.pool.clone(),
+                key,
+                token,
+            }),
+            None => Err(Error::SessionLocked(session_id.to_string()).into()),
+        }
+    }
+
+    /// like [`lock`](Self::lock), but waits for the session to be unlocked
+    /// for up to `timeout`, retrying with an exponential backoff
+    pub async fn lock_with_timeout(
+        &self,
+        session_id: &str,
+        ttl: Duration,
+        timeout: Duration,
+    ) -> Result<SessionLock> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let error = match self.lock(session_id, ttl).await {
+                Err(error) if matches!(error.downcast_ref(), Some(Error::SessionLocked(_))) => {
+                    error
+                }
+                result => return result,
+            };
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(error);
+            }
+            tokio::time::sleep(backoff.min(remaining)).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn lock_key(&self, session_id: &str) -> String {
+        self.prefix_key(&format!("{LOCK_KEY_PREFIX}{session_id}"))
+    }
+}
+
+/// the ttl of a lock in milliseconds, at least one
+fn millis(ttl: Duration) -> i64 {
+    crate::millis(ttl).max(1)
+}
@@ -0,0 +1,257 @@
+//! resolving conflicting changes of versioned sessions
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::Arc,
+};
+
+use async_session::{Result, Session};
+
+use crate::{meta, storage, RedisSessionStore};
+
+/// how often a conflict is merged before giving up with
+/// [`Error::VersionConflict`](crate::Error::VersionConflict), when the
+/// session keeps changing in between
+const MERGE_ATTEMPTS: usize = 10;
+
+type MergeFn = dyn Fn(&Session, &mut Session) -> Result + Send + Sync;
+
+/// the values of the keys of a session, by key
+pub(crate) type Snapshot = HashMap<String, String>;
+
+/// how storing a [versioned session](crate::RedisSessionStoreBuilder::versioned_sessions)
+/// resolves a conflict with a session stored by someone else since it was
+/// loaded. the strategy merges the stored session into the incoming one,
+/// which is then stored if the session did not change again in the meantime.
+///
+/// keys starting with `__afs:` are reserved for the store and not merged
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum MergeStrategy {
+    /// fails with [`Error::VersionConflict`](crate::Error::VersionConflict)
+    #[default]
+    Fail,
+    /// keeps the keys the incoming session changed, added or removed since
+    /// it was loaded, and takes every other key from the stored session.
+    /// sessions that were not loaded through the store keep their values,
+    /// adding the keys only the stored session has
+    LastWriterWinsPerKey,
+    /// keeps the values of the stored session, adding the keys only the
+    /// incoming session has
+    KeepExisting,
+    /// calls the function with the stored session and the incoming session,
+    /// which it changes into the session to store
+    Custom(Arc<MergeFn>),
+}
+
+impl MergeStrategy {
+    /// merges conflicts with a function receiving the stored session and the
+    /// incoming session, which it changes into the session to store
+    pub fn custom(
+        merge: impl Fn(&Session, &mut Session) -> Result + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom(Arc::new(merge))
+    }
+
+    /// merges the stored session into the incoming one, which had the
+    /// `loaded` values when it was loaded, if known
+    pub(crate) fn merge(
+        &self,
+        stored: &Session,
+        incoming: &mut Session,
+        loaded: Option<&Snapshot>,
+    ) -> Result {
+        let overwrite = match self {
+            Self::Fail => unreachable!("conflicts are not merged with MergeStrategy::Fail"),
+            Self::LastWriterWinsPerKey => false,
+            Self::KeepExisting => true,
+            Self::Custom(merge) => return merge(stored, incoming),
+        };
+
+        if let (false, Some(loaded)) = (overwrite, loaded) {
+            let stored = snapshot(stored)?;
+            let keys: BTreeSet<&String> = stored.keys().chain(loaded.keys()).collect();
+            for key in keys {
+                if incoming.get_raw(key).as_ref() != loaded.get(key) {
+                    continue;
+                }
+                match stored.get(key) {
+                    Some(value) => incoming.insert_raw(key, value.clone()),
+                    None => incoming.remove(key),
+                }
+            }
+            return Ok(());
+        }
+
+        for (key, value) in storage::entries(stored)? {
+            if !storage::is_reserved(&key) && (overwrite || incoming.get_raw(&key).is_none()) {
+                incoming.insert_raw(&key, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the values of the keys of a session that are not reserved for the store
+fn snapshot(session: &Session) -> Result<Snapshot> {
+    Ok(storage::entries(session)?
+        .into_iter()
+        .filter(|(key, _)| !storage::is_reserved(key))
+        .collect())
+}
+
+/// takes the values a session had when it was loaded out of it
+pub(crate) fn take_loaded(session: &mut Session) -> Option<Snapshot> {
+    let loaded = session.get(meta::LOADED);
+    if loaded.is_some() {
+        session.remove(meta::LOADED);
+    }
+    loaded
+}
+
+impl RedisSessionStore {
+    /// keeps the values of a loaded session in it, so
+    /// [`MergeStrategy::LastWriterWinsPerKey`] can tell which keys changed
+    /// since. the session does not count as changed by it
+    pub(crate) fn remember_loaded(&self, session: &mut Session) -> Result {
+        if !self.versioned_sessions
+            || !matches!(self.merge_strategy, MergeStrategy::LastWriterWinsPerKey)
+        {
+            return Ok(());
+        }
+        let changed = session.data_changed();
+        session.insert(meta::LOADED, snapshot(session)?)?;
+        if !changed {
+            session.reset_data_changed();
+        }
+        Ok(())
+    }
+
+    /// merges the stored session into a session that conflicted with it and
+    /// writes it again, as long as it keeps conflicting. returns the result of
+    /// the last write
+    pub(crate) async fn merge_session(
+        &self,
+        session: &mut Session,
+        mut loaded: Option<Snapshot>,
+        keys: Vec<String>,
+        layout: &str,
+        expiration: &str,
+        now_millis: i64,
+    ) -> Result<i64> {
+        for _ in 0..MERGE_ATTEMPTS {
+            // a destroyed or expired session is not brought back
+            let stored = self
+                .fetch_sessions(vec![session.id().to_string()])
+                .await?
+                .pop();
+            let Some(stored) = stored.transpose()? else {
+                return Ok(-1);
+            };
+            let Some(version) = meta::version(&stored) else {
+                return Ok(-1);
+            };
+
+            self.merge_strategy
+                .merge(&stored, session, loaded.as_ref())?;
+            // a further conflict is merged against the session merged here
+            if loaded.is_some() {
+                loaded = Some(snapshot(&stored)?);
+            }
+            meta::set(session, meta::VERSION, version.to_string());
+            let result = self
+                .write_session(session, keys.clone(), layout, expiration, now_millis, false)
+                .await?;
+            if result != -1 {
+                return Ok(result);
+            }
+        }
+        Ok(-1)
+    }
+}
+
+impl fmt::Debug for MergeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fail => f.write_str("Fail"),
+            Self::LastWriterWinsPerKey => f.write_str("LastWriterWinsPerKey"),
+            Self::KeepExisting => f.write_str("KeepExisting"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> Result<(Session, Session)> {
+        let mut stored = Session::new();
+        stored.insert("theme", "dark")?;
+        stored.insert("cart", vec!["apple"])?;
+        let mut incoming = Session::new();
+        incoming.insert("cart", vec!["pear"])?;
+        Ok((stored, incoming))
+    }
+
+    #[test]
+    fn merging_with_builtin_strategies() -> Result {
+        let (stored, mut incoming) = sessions()?;
+        MergeStrategy::LastWriterWinsPerKey.merge(&stored, &mut incoming, None)?;
+        assert_eq!(Some("dark".to_string()), incoming.get("theme"));
+        assert_eq!(Some(vec!["pear".to_string()]), incoming.get("cart"));
+
+        let (stored, mut incoming) = sessions()?;
+        MergeStrategy::KeepExisting.merge(&stored, &mut incoming, None)?;
+        assert_eq!(Some("dark".to_string()), incoming.get("theme"));
+        assert_eq!(Some(vec!["apple".to_string()]), incoming.get("cart"));
+        Ok(())
+    }
+
+    #[test]
+    fn merging_the_keys_changed_since_loading() -> Result {
+        let mut loaded = Session::new();
+        for key in ["a", "b", "c", "d"] {
+            loaded.insert(key, 1)?;
+        }
+        let snapshot = snapshot(&loaded)?;
+
+        // both sides hold every key, but each changed different ones
+        let mut stored = Session::new();
+        stored.insert("a", 2)?;
+        stored.insert("b", 1)?;
+        stored.insert("d", 1)?;
+        stored.insert("e", 1)?;
+        let mut incoming = Session::new();
+        incoming.insert("a", 1)?;
+        incoming.insert("b", 2)?;
+        incoming.insert("c", 1)?;
+
+        MergeStrategy::LastWriterWinsPerKey.merge(&stored, &mut incoming, Some(&snapshot))?;
+        assert_eq!(Some(2), incoming.get::<i32>("a"));
+        assert_eq!(Some(2), incoming.get::<i32>("b"));
+        assert_eq!(None, incoming.get::<i32>("c"));
+        assert_eq!(None, incoming.get::<i32>("d"));
+        assert_eq!(Some(1), incoming.get::<i32>("e"));
+        Ok(())
+    }
+
+    #[test]
+    fn merging_with_a_custom_strategy() -> Result {
+        let strategy = MergeStrategy::custom(|stored, incoming| {
+            let mut cart: Vec<String> = stored.get("cart").unwrap_or_default();
+            cart.extend(incoming.get::<Vec<String>>("cart").unwrap_or_default());
+            Ok(incoming.insert("cart", cart)?)
+        });
+
+        let (stored, mut incoming) = sessions()?;
+        strategy.merge(&stored, &mut incoming, None)?;
+        assert_eq!(None, incoming.get::<String>("theme"));
+        assert_eq!(
+            Some(vec!["apple".to_string(), "pear".to_string()]),
+            incoming.get("cart")
+        );
+        Ok(())
+    }
+}
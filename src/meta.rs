@@ -0,0 +1,50 @@
+//! keys the store keeps in the session data, to carry its own state from
+//! loading a session to storing it again. they all start with `__afs:`
+
+use async_session::Session;
+
+/// the expiry of the session when it was last stored, in unix milliseconds
+/// or `null`
+pub(crate) const STORED_EXPIRY: &str = "__afs:stored_expiry";
+
+/// when the session was first stored, in unix milliseconds
+pub(crate) const CREATED_AT: &str = "__afs:created_at";
+/// when the session was last loaded or first stored, in unix milliseconds
+pub(crate) const LAST_ACCESS: &str = "__afs:last_access";
+/// the id of the user the session is bound to, as a json string
+pub(crate) const USER_ID: &str = "__afs:user_id";
+/// how many times the session was stored, with versioned sessions
+pub(crate) const VERSION: &str = "__afs:version";
+/// the values of the session keys when the session was loaded, as a json
+/// object, to merge conflicts per key. taken out before storing the session
+pub(crate) const LOADED: &str = "__afs:loaded";
+
+/// reads a store key holding a unix timestamp in milliseconds
+pub(crate) fn millis(session: &Session, key: &str) -> Option<i64> {
+    session.get_raw(key)?.parse().ok()
+}
+
+/// sets a store key, leaving the session untouched if it already holds the value
+pub(crate) fn set(session: &mut Session, key: &str, value: String) {
+    if session.get_raw(key).as_ref() != Some(&value) {
+        session.insert_raw(key, value);
+    }
+}
+
+/// the current expiry of the session in the format of [`STORED_EXPIRY`]
+pub(crate) fn expiry(session: &Session) -> String {
+    session.expiry().map_or_else(
+        || "null".into(),
+        |expiry| expiry.timestamp_millis().to_string(),
+    )
+}
+
+/// the id of the user the session is bound to
+pub(crate) fn user_id(session: &Session) -> Option<String> {
+    session.get(USER_ID)
+}
+
+/// the version of the session when it was loaded
+pub(crate) fn version(session: &Session) -> Option<u64> {
+    session.get_raw(VERSION)?.parse().ok()
+}
@@ -0,0 +1,67 @@
+//! moving sessions to a new id, to prevent session fixation
+
+use async_session::{Result, Session};
+use fred::types::RedisValue;
+
+use crate::{
+    deep_copy, meta, scripts, storage, Error, RedisSessionStore, StorageMode, ID_ATTEMPTS,
+};
+
+impl RedisSessionStore {
+    /// gives a stored session a new id, in a single step that also
+    /// invalidates the old id, and returns the new cookie value. the stored
+    /// session is replaced with the given one, keeping its ttl. returns `None`
+    /// if the session does not exist anymore, and fails with
+    /// [`Error::IdCollision`] if every new id it tried was taken. the old id
+    /// gets a [tombstone](crate::RedisSessionStoreBuilder::tombstones) if
+    /// enabled.
+    ///
+    /// with [versioned sessions](crate::RedisSessionStoreBuilder::versioned_sessions)
+    /// the session is not checked for conflicts
+    pub async fn regenerate(&self, session: Session) -> Result<Option<String>> {
+        let mut session = deep_copy(&session)?;
+        let old_id = session.id().to_string();
+        let user_id = meta::user_id(&session);
+        if self.versioned_sessions {
+            let version = meta::version(&session).unwrap_or(0) + 1;
+            meta::set(&mut session, meta::VERSION, version.to_string());
+        }
+
+        // a new id that is taken is replaced with another one
+        let mut result = -2;
+        for _ in 0..ID_ATTEMPTS {
+            session.regenerate();
+            let id = session.id().to_string();
+            let (mut keys, layout) = self.script_keys(&old_id, user_id.as_deref());
+            keys.insert(1, self.prefix_key(&id));
+
+            let mut args: Vec<RedisValue> = vec![
+                layout.into(),
+                old_id.as_str().into(),
+                id.into(),
+                self.storage.type_name().into(),
+                self.tombstone_millis().unwrap_or_default().into(),
+            ];
+            match self.storage {
+                StorageMode::String => args.push(self.encode_stored(&session)?.into()),
+                StorageMode::Hash => {
+                    for (field, value) in storage::to_fields(&session)? {
+                        args.extend([field.into(), value.into()]);
+                    }
+                }
+            }
+
+            result = scripts::regenerate_session()
+                .evalsha_with_reload(self.pool.next(), keys, args)
+                .await?;
+            if result != -2 {
+                break;
+            }
+        }
+        if result == -2 {
+            return Err(Error::IdCollision(session.id().to_string()).into());
+        }
+        self.invalidate_cached(&old_id).await;
+        Ok((result == 1).then(|| session.into_cookie_value()).flatten())
+    }
+}
@@ -0,0 +1,543 @@
+//! lua scripts for the operations that have to be atomic on the redis side
+//!
+//! scripts that read, write or delete a single session take its key as
+//! `KEYS[1]`, followed by the optional keys named in `ARGV[1]`, in that
+//! order: `i` for the session index, `u` for the set of sessions of the user
+//! the session is bound to and `t` for the tombstone of the session. they
+//! keep those up to date. index scores are the unix time in
+//! milliseconds the session expires at, or `+inf`, user set scores are the
+//! unix time in milliseconds the session was first stored at, or last
+//! loaded or stored at when evicting the least recently used sessions
+
+use std::sync::OnceLock;
+
+use fred::types::Script;
+
+macro_rules! script {
+    ($(#[$meta:meta])* $name:ident, $lua:expr) => {
+        $(#[$meta])*
+        pub(crate) fn $name() -> &'static Script {
+            static SCRIPT: OnceLock<Script> = OnceLock::new();
+            SCRIPT.get_or_init(|| Script::from_lua($lua))
+        }
+    };
+}
+
+/// reads the optional keys named in `ARGV[1]`, which follow `KEYS[1]` or the
+/// given key, into `index`, `user` and `tombstone`
+macro_rules! session_keys {
+    () => {
+        session_keys!(1)
+    };
+    ($last:literal) => {
+        concat!(
+            r#"
+            local index, user, tombstone
+            for i = 1, #ARGV[1] do
+                local key = KEYS["#,
+            $last,
+            r#" + i]
+                local name = string.sub(ARGV[1], i, i)
+                if name == 'i' then
+                    index = key
+                elseif name == 'u' then
+                    user = key
+                elseif name == 't' then
+                    tombstone = key
+                end
+            end
+            "#
+        )
+    };
+}
+
+/// the start of the scripts that store a session, failing with -3 if the
+/// session has a tombstone
+macro_rules! check_tombstone {
+    () => {
+        r#"
+        if tombstone and redis.call('EXISTS', tombstone) == 1 then
+            return {-3}
+        end
+        "#
+    };
+}
+
+/// the start of the script that stores a string, failing with -1 unless the
+/// first 9 bytes of `KEYS[1]`, the version header, are `ARGV[7]`. `ARGV[7]`
+/// is `-` to skip the check, and empty for a new session, failing with -2 if
+/// `KEYS[1]` exists
+macro_rules! check_string_version {
+    () => {
+        r#"
+        if ARGV[7] == '' then
+            if redis.call('EXISTS', KEYS[1]) == 1 then
+                return {-2}
+            end
+        elseif ARGV[7] ~= '-' and redis.call('GETRANGE', KEYS[1], 0, 8) ~= ARGV[7] then
+            return {-1}
+        end
+        "#
+    };
+}
+
+/// the start of the script that stores a hash, failing with -1 unless its
+/// version field is `ARGV[7]`. `ARGV[7]` is `-` to skip the check, and empty
+/// for a new session, failing with -2 if `KEYS[1]` exists
+macro_rules! check_hash_version {
+    () => {
+        r#"
+        if ARGV[7] == '' then
+            if redis.call('EXISTS', KEYS[1]) == 1 then
+                return {-2}
+            end
+        elseif ARGV[7] ~= '-' and redis.call('HGET', KEYS[1], '__afs:version') ~= ARGV[7] then
+            return {-1}
+        end
+        "#
+    };
+}
+
+/// keeps the user set `user` until the unix time in milliseconds `ARGV[2]`
+/// at least, or forever if it is empty, so it outlives its sessions.
+/// `fresh` tells whether the set was just created and has no ttl yet, as a
+/// set without one already holds a session without an expiry
+macro_rules! extend_user_set {
+    () => {
+        r#"
+        local ttl = redis.call('PTTL', user)
+        if ARGV[2] == '' then
+            redis.call('PERSIST', user)
+        elseif fresh then
+            redis.call('PEXPIREAT', user, ARGV[2])
+        elseif ttl >= 0 then
+            local time = redis.call('TIME')
+            local now = time[1] * 1000 + math.floor(time[2] / 1000)
+            if now + ttl < tonumber(ARGV[2]) then
+                redis.call('PEXPIREAT', user, ARGV[2])
+            end
+        end
+        "#
+    };
+}
+
+/// the start of the scripts that store a session, enforcing the session
+/// limit `ARGV[5]` of the user set, if not empty, by rejecting the session
+/// or evicting the sessions with the lowest scores as `ARGV[6]` says, and
+/// adding the session to the user set, which is kept at least until the
+/// session expires at `ARGV[2]`. the ids of evicted sessions are collected
+/// in `evicted`, and they get tombstones that expire in `ARGV[8]`
+/// milliseconds if not empty, next to the tombstone key of the session
+macro_rules! enforce_session_limit {
+    () => {
+        concat!(
+            r#"
+        local evicted = {}
+        local limit = tonumber(ARGV[5])
+        if user and limit and not redis.call('ZSCORE', user, ARGV[3]) then
+            local prefix = string.sub(KEYS[1], 1, #KEYS[1] - #ARGV[3])
+            for _, id in ipairs(redis.call('ZRANGE', user, 0, -1)) do
+                if redis.call('EXISTS', prefix .. id) == 0 then
+                    redis.call('ZREM', user, id)
+                end
+            end
+            local excess = redis.call('ZCARD', user) - limit + 1
+            if excess > 0 then
+                if ARGV[6] == 'reject' then
+                    return {0}
+                end
+                local tombstones = tombstone and ARGV[8] ~= ''
+                    and string.sub(tombstone, 1, #tombstone - #ARGV[3])
+                for _, id in ipairs(redis.call('ZRANGE', user, 0, excess - 1)) do
+                    redis.call('DEL', prefix .. id)
+                    redis.call('ZREM', user, id)
+                    table.insert(evicted, id)
+                    if tombstones then
+                        redis.call('SET', tombstones .. id, '1', 'PX', ARGV[8])
+                    end
+                    if index then
+                        redis.call('ZREM', index, id)
+                    end
+                end
+            end
+        end
+        if user then
+            local fresh = redis.call('EXISTS', user) == 0
+            if ARGV[6] == 'lru' then
+                redis.call('ZADD', user, ARGV[4], ARGV[3])
+            else
+                redis.call('ZADD', user, 'NX', ARGV[4], ARGV[3])
+            end
+        "#,
+            extend_user_set!(),
+            r#"
+        end
+        "#
+        )
+    };
+}
+
+script!(
+    /// deletes `KEYS[1]` only if it still holds `ARGV[2]`, so a session
+    /// rewritten in the meantime is left alone. `ARGV[3]` is the session id
+    delete_if_unchanged,
+    concat!(
+        session_keys!(),
+        r#"
+        if redis.call('GET', KEYS[1]) == ARGV[2] then
+            if index then
+                redis.call('ZREM', index, ARGV[3])
+            end
+            if user then
+                redis.call('ZREM', user, ARGV[3])
+            end
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        "#
+    )
+);
+
+script!(
+    /// deletes the hash `KEYS[1]` only if its expiry field `ARGV[2]` still
+    /// holds `ARGV[3]`, which is empty for a session without an expiry.
+    /// `ARGV[4]` is the session id
+    delete_hash_if_unchanged,
+    concat!(
+        session_keys!(),
+        r#"
+        if (redis.call('HGET', KEYS[1], ARGV[2]) or '') == ARGV[3] then
+            if index then
+                redis.call('ZREM', index, ARGV[4])
+            end
+            if user then
+                redis.call('ZREM', user, ARGV[4])
+            end
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        "#
+    )
+);
+
+script!(
+    /// deletes the session `KEYS[1]` with the id `ARGV[2]`, leaving a
+    /// tombstone that expires in `ARGV[3]` milliseconds if given
+    delete_session,
+    concat!(
+        session_keys!(),
+        r#"
+        if tombstone and ARGV[3] then
+            redis.call('SET', tombstone, '1', 'PX', ARGV[3])
+        end
+        if index then
+            redis.call('ZREM', index, ARGV[2])
+        end
+        if user then
+            redis.call('ZREM', user, ARGV[2])
+        end
+        return redis.call('DEL', KEYS[1])
+        "#
+    )
+);
+
+script!(
+    /// sets the string `KEYS[1]` to `ARGV[9]` and expires it at the unix time
+    /// in milliseconds `ARGV[2]`, if not empty. `ARGV[3]` is the session id
+    /// and `ARGV[4]` the current unix time in milliseconds. returns `{1}`
+    /// followed by the ids of the sessions evicted by the session limit, or
+    /// `{0}` if the session limit rejected the session, `{-1}` on a version
+    /// conflict, `{-2}` if a new session collides with an existing one and
+    /// `{-3}` if the session has a tombstone
+    store_string,
+    concat!(
+        session_keys!(),
+        check_tombstone!(),
+        check_string_version!(),
+        enforce_session_limit!(),
+        r#"
+        if ARGV[2] == '' then
+            redis.call('SET', KEYS[1], ARGV[9])
+        else
+            redis.call('SET', KEYS[1], ARGV[9], 'PXAT', ARGV[2])
+        end
+        if index then
+            redis.call('ZADD', index, ARGV[2] == '' and '+inf' or ARGV[2], ARGV[3])
+        end
+        return {1, unpack(evicted)}
+        "#
+    )
+);
+
+script!(
+    /// replaces the hash `KEYS[1]` with the field value pairs in `ARGV[9..]`
+    /// and expires it at the unix time in milliseconds `ARGV[2]`, if not empty.
+    /// `ARGV[3]` is the session id and `ARGV[4]` the current unix time in
+    /// milliseconds. returns like [`store_string`]. stale fields are removed
+    /// one by one, as deleting the hash would notify a `del` event
+    store_hash,
+    concat!(
+        session_keys!(),
+        check_tombstone!(),
+        check_hash_version!(),
+        enforce_session_limit!(),
+        r#"
+        local fields = {}
+        for i = 9, #ARGV, 2 do
+            fields[ARGV[i]] = true
+        end
+        for _, field in ipairs(redis.call('HKEYS', KEYS[1])) do
+            if not fields[field] then
+                redis.call('HDEL', KEYS[1], field)
+            end
+        end
+        redis.call('HSET', KEYS[1], unpack(ARGV, 9))
+        if ARGV[2] == '' then
+            redis.call('PERSIST', KEYS[1])
+        else
+            redis.call('PEXPIREAT', KEYS[1], ARGV[2])
+        end
+        if index then
+            redis.call('ZADD', index, ARGV[2] == '' and '+inf' or ARGV[2], ARGV[3])
+        end
+        return {1, unpack(evicted)}
+        "#
+    )
+);
+
+script!(
+    /// sets the field `ARGV[1]` of the hash `KEYS[1]` to `ARGV[2]` if the hash
+    /// exists, returning whether it did. bumps the version field if `ARGV[3]`
+    /// is given
+    set_field_if_exists,
+    r#"
+    if redis.call('EXISTS', KEYS[1]) == 1 then
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+        if ARGV[3] then
+            redis.call('HINCRBY', KEYS[1], '__afs:version', 1)
+        end
+        return 1
+    end
+    return 0
+    "#
+);
+
+script!(
+    /// removes the field `ARGV[1]` of the hash `KEYS[1]`, returning whether it
+    /// existed. bumps the version field if it did and `ARGV[2]` is given
+    remove_field,
+    r#"
+    local removed = redis.call('HDEL', KEYS[1], ARGV[1])
+    if removed == 1 and ARGV[2] then
+        redis.call('HINCRBY', KEYS[1], '__afs:version', 1)
+    end
+    return removed
+    "#
+);
+
+script!(
+    /// expires `KEYS[1]` at the unix time in milliseconds `ARGV[2]`, or
+    /// persists it if `ARGV[2]` is empty. `ARGV[3]` is the session id.
+    /// returns whether the key exists
+    refresh_ttl,
+    concat!(
+        session_keys!(),
+        r#"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        if ARGV[2] == '' then
+            redis.call('PERSIST', KEYS[1])
+        else
+            redis.call('PEXPIREAT', KEYS[1], ARGV[2])
+        end
+        if index then
+            redis.call('ZADD', index, ARGV[2] == '' and '+inf' or ARGV[2], ARGV[3])
+        end
+        if user then
+            local fresh = false
+        "#,
+        extend_user_set!(),
+        r#"
+        end
+        return 1
+        "#
+    )
+);
+
+script!(
+    /// keeps the user set of the session `KEYS[1]` until the unix time in
+    /// milliseconds `ARGV[2]` at least, after sliding expiration extended
+    /// the session
+    extend_user_set,
+    concat!(
+        session_keys!(),
+        r#"
+        local fresh = false
+        "#,
+        extend_user_set!()
+    )
+);
+
+script!(
+    /// returns the string `KEYS[1]` and expires it in `ARGV[2]` milliseconds,
+    /// for stores with a session index. `ARGV[3]` is the session id and
+    /// `ARGV[4]` its new index score
+    get_and_expire,
+    concat!(
+        session_keys!(),
+        r#"
+        local value = redis.call('GET', KEYS[1])
+        if value then
+            redis.call('PEXPIRE', KEYS[1], ARGV[2])
+            if index then
+                redis.call('ZADD', index, ARGV[4], ARGV[3])
+            end
+        end
+        return value
+        "#
+    )
+);
+
+script!(
+    /// returns the fields of the hash `KEYS[1]` and expires it in `ARGV[2]`
+    /// milliseconds. `ARGV[3]` is the session id and `ARGV[4]` its new index
+    /// score
+    hgetall_and_expire,
+    concat!(
+        session_keys!(),
+        r#"
+        local fields = redis.call('HGETALL', KEYS[1])
+        if #fields > 0 then
+            redis.call('PEXPIRE', KEYS[1], ARGV[2])
+            if index then
+                redis.call('ZADD', index, ARGV[4], ARGV[3])
+            end
+        end
+        return fields
+        "#
+    )
+);
+
+script!(
+    /// removes the entries of the session index `KEYS[1]` that expired
+    /// before the unix time in milliseconds `ARGV[1]` and returns how many
+    /// are left
+    count_index,
+    r#"
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', '(' .. ARGV[1])
+    return redis.call('ZCARD', KEYS[1])
+    "#
+);
+
+script!(
+    /// removes the sessions that no longer exist from the user set `KEYS[1]`
+    /// and returns the ids of the others, oldest first. `ARGV[1]` is the key
+    /// prefix of the store
+    user_sessions,
+    r#"
+    local live = {}
+    for _, id in ipairs(redis.call('ZRANGE', KEYS[1], 0, -1)) do
+        if redis.call('EXISTS', ARGV[1] .. id) == 1 then
+            table.insert(live, id)
+        else
+            redis.call('ZREM', KEYS[1], id)
+        end
+    end
+    return live
+    "#
+);
+
+script!(
+    /// deletes every session in the user set `KEYS[1]` and the set itself,
+    /// removing them from the session index `KEYS[2]` if given. `ARGV[1]` is
+    /// the key prefix of the store. leaves tombstones under the key prefix
+    /// `ARGV[2]` that expire in `ARGV[3]` milliseconds if given. returns the
+    /// number of sessions deleted, followed by the ids in the set
+    destroy_user_sessions,
+    r#"
+    local deleted = {0}
+    for _, id in ipairs(redis.call('ZRANGE', KEYS[1], 0, -1)) do
+        deleted[1] = deleted[1] + redis.call('DEL', ARGV[1] .. id)
+        table.insert(deleted, id)
+        if ARGV[2] then
+            redis.call('SET', ARGV[2] .. id, '1', 'PX', ARGV[3])
+        end
+        if KEYS[2] then
+            redis.call('ZREM', KEYS[2], id)
+        end
+    end
+    redis.call('DEL', KEYS[1])
+    return deleted
+    "#
+);
+
+script!(
+    /// moves the session `KEYS[1]` to the key `KEYS[2]`, keeping its ttl.
+    /// unlike in the other scripts, the optional keys named in `ARGV[1]`
+    /// follow `KEYS[2]`. `ARGV[2]` and `ARGV[3]` are the old and the new
+    /// session id. the new value is `ARGV[6]` if `ARGV[4]` is `string`, or the
+    /// field value pairs from `ARGV[6]` on if it is `hash`. the old id gets a
+    /// tombstone that expires in `ARGV[5]` milliseconds if not empty. returns
+    /// 1 if the session was moved, 0 if it does not exist and -2 if the new
+    /// id is taken
+    regenerate_session,
+    concat!(
+        session_keys!(2),
+        r#"
+        local ttl = redis.call('PTTL', KEYS[1])
+        if ttl == -2 then
+            return 0
+        end
+        if redis.call('EXISTS', KEYS[2]) == 1 then
+            return -2
+        end
+        if ARGV[4] == 'string' then
+            redis.call('SET', KEYS[2], ARGV[6])
+        else
+            redis.call('HSET', KEYS[2], unpack(ARGV, 6))
+        end
+        if ttl > 0 then
+            redis.call('PEXPIRE', KEYS[2], ttl)
+        end
+        redis.call('DEL', KEYS[1])
+        if tombstone and ARGV[5] ~= '' then
+            redis.call('SET', tombstone, '1', 'PX', ARGV[5])
+        end
+        local function rename(set)
+            local score = set and redis.call('ZSCORE', set, ARGV[2])
+            if score then
+                redis.call('ZREM', set, ARGV[2])
+                redis.call('ZADD', set, score, ARGV[3])
+            end
+        end
+        rename(index)
+        rename(user)
+        return 1
+        "#
+    )
+);
+
+script!(
+    /// deletes the lock `KEYS[1]` if it still holds the token `ARGV[1]`.
+    /// returns whether it was deleted
+    release_lock,
+    r#"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0
+    "#
+);
+
+script!(
+    /// expires the lock `KEYS[1]` in `ARGV[2]` milliseconds if it still holds
+    /// the token `ARGV[1]`. returns whether it was extended
+    extend_lock,
+    r#"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    end
+    return 0
+    "#
+);
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use async_session::{serde_json, Result, Session};
+use fred::types::ScanType;
+
+/// hash field holding the session id
+pub(crate) const ID_FIELD: &str = "__afs:id";
+/// hash field holding the session expiry as an rfc 3339 timestamp
+pub(crate) const EXPIRY_FIELD: &str = "__afs:expiry";
+/// prefix of the hash fields reserved for the store
+const RESERVED_PREFIX: &str = "__afs:";
+
+/// how sessions are laid out in redis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageMode {
+    /// every session is a string holding the session encoded with the
+    /// [codec](crate::SessionCodec)
+    #[default]
+    String,
+    /// every session is a hash with one field per session key, holding the
+    /// json value of that key. this allows reading and updating single keys
+    /// with [`RedisSessionStore::get_field`](crate::RedisSessionStore::get_field)
+    /// and friends. keys starting with `__afs:` are reserved for the store.
+    ///
+    /// codecs, compression and encryption do not apply to hashes
+    Hash,
+}
+
+impl StorageMode {
+    pub(crate) fn scan_type(&self) -> ScanType {
+        match self {
+            Self::String => ScanType::String,
+            Self::Hash => ScanType::Hash,
+        }
+    }
+
+    /// the name of the redis type sessions are stored as
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Hash => "hash",
+        }
+    }
+}
+
+pub(crate) fn is_reserved(field: &str) -> bool {
+    field.starts_with(RESERVED_PREFIX)
+}
+
+/// flattens a session into the field value pairs of its hash
+pub(crate) fn to_fields(session: &Session) -> Result<Vec<(String, String)>> {
+    let mut fields = vec![(ID_FIELD.to_string(), session.id().to_string())];
+    if let Some(expiry) = session.expiry() {
+        fields.push((EXPIRY_FIELD.to_string(), expiry.to_rfc3339()));
+    }
+
+    for (key, value) in entries(session)? {
+        if key == ID_FIELD || key == EXPIRY_FIELD {
+            return Err(async_session::Error::msg(format!(
+                "session key `{key}` is reserved in hash storage mode"
+            )));
+        }
+        fields.push((key, value));
+    }
+
+    Ok(fields)
+}
+
+/// the keys of a session with their raw json values
+pub(crate) fn entries(session: &Session) -> Result<Vec<(String, String)>> {
+    let serde_json::Value::Object(mut object) = serde_json::to_value(session)? else {
+        unreachable!("sessions serialize to json objects");
+    };
+
+    let mut entries = Vec::new();
+    if let Some(serde_json::Value::Object(data)) = object.remove("data") {
+        for (key, value) in data {
+            if let serde_json::Value::String(value) = value {
+                entries.push((key, value));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// rebuilds a session from the fields of its hash, `None` if the hash
+/// does not exist
+pub(crate) fn from_fields(mut fields: HashMap<String, String>) -> Result<Option<Session>> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let Some(id) = fields.remove(ID_FIELD) else {
+        return Err(async_session::Error::msg("hash is not a stored session"));
+    };
+    let expiry = fields.remove(EXPIRY_FIELD);
+
+    Ok(Some(serde_json::from_value(serde_json::json!({
+        "id": id,
+        "expiry": expiry,
+        "data": fields,
+    }))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn fields_roundtrip() -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.insert("number", 42)?;
+        session.expire_in(Duration::from_secs(60));
+
+        let fields = to_fields(&session)?;
+        assert!(fields.contains(&("key".into(), r#""value""#.into())));
+        assert!(fields.contains(&("number".into(), "42".into())));
+
+        let loaded = from_fields(fields.into_iter().collect())?.unwrap();
+        assert_eq!(session.id(), loaded.id());
+        assert_eq!(session.expiry(), loaded.expiry());
+        assert_eq!(Some(42), loaded.get::<i32>("number"));
+        assert_eq!(2, loaded.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reserved_keys_are_rejected() -> Result {
+        let mut session = Session::new();
+        session.insert("__afs:id", "value")?;
+        assert!(to_fields(&session).is_err());
+
+        let mut session = Session::new();
+        session.insert_raw("__afs:stored_expiry", "null".into());
+        let loaded = from_fields(to_fields(&session)?.into_iter().collect())?.unwrap();
+        assert_eq!(Some("null".into()), loaded.get_raw("__afs:stored_expiry"));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_hashes_are_not_sessions() -> Result {
+        assert!(from_fields(HashMap::new())?.is_none());
+        assert!(from_fields([("key".into(), "1".into())].into()).is_err());
+        Ok(())
+    }
+}
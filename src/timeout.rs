@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use async_session::Session;
+
+use crate::{meta, millis};
+
+/// limits on how long a session stays valid, on top of its own expiry
+///
+/// the store records when a session was first stored and when it was last
+/// loaded in the session data, under the reserved `__afs:created_at` and
+/// `__afs:last_access` keys. [`load_session`](async_session::SessionStore::load_session)
+/// rejects and deletes sessions past either limit, and
+/// [`store_session`](async_session::SessionStore::store_session) sets the
+/// redis ttl to whichever limit comes first
+///
+/// recording the last access changes the session on every load, so with an
+/// idle timeout loaded sessions are always written back when stored. use
+/// [sliding expiration](crate::RedisSessionStoreBuilder::sliding_expiration)
+/// for an idle timeout that only refreshes the ttl
+/// ```rust
+/// use std::time::Duration;
+/// use async_fred_session::TimeoutPolicy;
+///
+/// let policy = TimeoutPolicy::new()
+///     .idle(Duration::from_secs(30 * 60))
+///     .absolute(Duration::from_secs(12 * 60 * 60));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimeoutPolicy {
+    idle: Option<Duration>,
+    absolute: Option<Duration>,
+}
+
+impl TimeoutPolicy {
+    /// creates a policy without limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// invalidates sessions that have not been loaded for this long
+    pub fn idle(mut self, timeout: Duration) -> Self {
+        self.idle = Some(timeout);
+        self
+    }
+
+    /// invalidates sessions this long after they were first stored,
+    /// no matter how often they are used
+    pub fn absolute(mut self, timeout: Duration) -> Self {
+        self.absolute = Some(timeout);
+        self
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        [self.idle, self.absolute]
+            .into_iter()
+            .flatten()
+            .all(|timeout| timeout.as_millis() > 0)
+    }
+
+    pub(crate) fn has_idle(&self) -> bool {
+        self.idle.is_some()
+    }
+
+    /// the unix timestamp in milliseconds at which the first limit is reached
+    pub(crate) fn expires_at_millis(&self, session: &Session) -> Option<i64> {
+        let after = |key, timeout: Option<Duration>| {
+            let timeout = millis(timeout?);
+            meta::millis(session, key).map(|millis| millis.saturating_add(timeout))
+        };
+
+        match (
+            after(meta::LAST_ACCESS, self.idle),
+            after(meta::CREATED_AT, self.absolute),
+        ) {
+            (Some(idle), Some(absolute)) => Some(idle.min(absolute)),
+            (idle, absolute) => idle.or(absolute),
+        }
+    }
+
+    /// records the creation and access times of a session that does not
+    /// have them yet
+    pub(crate) fn record(&self, session: &mut Session, now_millis: i64) {
+        if self.absolute.is_some() && session.get_raw(meta::CREATED_AT).is_none() {
+            meta::set(session, meta::CREATED_AT, now_millis.to_string());
+        }
+        if self.idle.is_some() && session.get_raw(meta::LAST_ACCESS).is_none() {
+            meta::set(session, meta::LAST_ACCESS, now_millis.to_string());
+        }
+    }
+
+    /// records that a session was loaded
+    pub(crate) fn touch(&self, session: &mut Session, now_millis: i64) {
+        if self.idle.is_some() {
+            meta::set(session, meta::LAST_ACCESS, now_millis.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_limit_wins() {
+        let policy = TimeoutPolicy::new()
+            .idle(Duration::from_millis(100))
+            .absolute(Duration::from_millis(1000));
+
+        let mut session = Session::new();
+        assert_eq!(None, policy.expires_at_millis(&session));
+
+        policy.record(&mut session, 0);
+        assert_eq!(Some(100), policy.expires_at_millis(&session));
+
+        policy.touch(&mut session, 950);
+        policy.record(&mut session, 2000);
+        assert_eq!(Some(1000), policy.expires_at_millis(&session));
+    }
+
+    #[test]
+    fn unused_limits_are_not_recorded() {
+        let mut session = Session::new();
+        TimeoutPolicy::new()
+            .absolute(Duration::from_secs(1))
+            .record(&mut session, 0);
+
+        assert!(session.get_raw(meta::CREATED_AT).is_some());
+        assert!(session.get_raw(meta::LAST_ACCESS).is_none());
+        assert!(!TimeoutPolicy::new().idle(Duration::ZERO).is_valid());
+    }
+}
@@ -0,0 +1,43 @@
+//! tombstones left by destroyed sessions, to block storing them again
+
+use std::{fmt, sync::Arc};
+
+use async_session::Result;
+use fred::interfaces::KeysInterface;
+
+use crate::{millis, RedisSessionStore, TOMBSTONE_KEY_PREFIX};
+
+/// the hook loads of [tombstoned](crate::RedisSessionStoreBuilder::tombstones)
+/// sessions are reported to
+#[derive(Clone)]
+pub(crate) struct TombstoneAudit(pub(crate) Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for TombstoneAudit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TombstoneAudit(..)")
+    }
+}
+
+impl RedisSessionStore {
+    pub(crate) fn tombstone_key(&self, id: &str) -> String {
+        self.prefix_key(&format!("{TOMBSTONE_KEY_PREFIX}{id}"))
+    }
+
+    /// the ttl of tombstones in milliseconds, if enabled
+    pub(crate) fn tombstone_millis(&self) -> Option<String> {
+        self.tombstone_ttl.map(|ttl| millis(ttl).max(1).to_string())
+    }
+
+    /// reports the load of a session that does not exist to the audit hook,
+    /// if the session has a tombstone
+    pub(crate) async fn report_tombstone(&self, id: &str) -> Result {
+        let Some(TombstoneAudit(audit)) = &self.tombstone_audit else {
+            return Ok(());
+        };
+        let tombstoned: bool = self.pool.exists(self.tombstone_key(id)).await?;
+        if tombstoned {
+            audit(id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! binding sessions to users, to list and revoke all sessions of a user
+
+use async_session::{Result, Session};
+use fred::{
+    interfaces::SortedSetsInterface,
+    types::{RedisValue, SetOptions},
+};
+
+use crate::{meta, millis, scripts, RedisSessionStore, USER_KEY_PREFIX};
+
+/// what storing a new session for a user that already has as many sessions
+/// as the [session limit](crate::RedisSessionStoreBuilder::session_limit)
+/// allows does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionLimitPolicy {
+    /// fail with [`Error::SessionLimitReached`](crate::Error::SessionLimitReached)
+    /// and leave the other sessions alone
+    Reject,
+    /// delete the session that was stored first
+    EvictOldest,
+    /// delete the session that was loaded or stored least recently
+    EvictLeastRecentlyUsed,
+}
+
+impl SessionLimitPolicy {
+    pub(crate) fn as_arg(&self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::EvictOldest => "oldest",
+            Self::EvictLeastRecentlyUsed => "lru",
+        }
+    }
+}
+
+impl RedisSessionStore {
+    /// binds a session to a user. once it is stored, the session shows up
+    /// in [`user_session_ids`](Self::user_session_ids) and is deleted by
+    /// [`destroy_user_sessions`](Self::destroy_user_sessions).
+    ///
+    /// the user id is kept in the session data under the reserved
+    /// `__afs:user_id` key. to move a session to another user, regenerate it
+    /// instead of binding it again, or it stays in the set of the first user
+    /// until it is deleted.
+    ///
+    /// every user has a sorted set of session ids next to the sessions,
+    /// which expires with the last of them. see
+    /// [`prefix`](crate::RedisSessionStoreBuilder::prefix) for running in a
+    /// cluster
+    pub fn bind_user(session: &mut Session, user_id: &str) -> Result {
+        session.insert(meta::USER_ID, user_id)?;
+        Ok(())
+    }
+
+    /// the id of the user a session is bound to with [`bind_user`](Self::bind_user)
+    pub fn user_id(session: &Session) -> Option<String> {
+        meta::user_id(session)
+    }
+
+    /// returns the ids of the live sessions of a user, oldest or least
+    /// recently used first, removing the ids of sessions that expired or
+    /// were deleted from the user set
+    pub async fn user_session_ids(&self, user_id: &str) -> Result<Vec<String>> {
+        Ok(scripts::user_sessions()
+            .evalsha_with_reload(
+                self.pool.next(),
+                self.user_key(user_id),
+                self.prefix_key(""),
+            )
+            .await?)
+    }
+
+    /// atomically deletes every session of a user, for example after a
+    /// password change. returns the number of sessions deleted
+    pub async fn destroy_user_sessions(&self, user_id: &str) -> Result<usize> {
+        let mut keys = vec![self.user_key(user_id)];
+        keys.extend(self.index_key.clone());
+        let mut args = vec![self.prefix_key("")];
+        if let Some(millis) = self.tombstone_millis() {
+            args.extend([self.tombstone_key(""), millis]);
+        }
+        let deleted: Vec<RedisValue> = scripts::destroy_user_sessions()
+            .evalsha_with_reload(self.pool.next(), keys, args)
+            .await?;
+        for id in &deleted[1..] {
+            if let Some(id) = id.as_str() {
+                self.invalidate_cached(&id).await;
+            }
+        }
+        Ok(deleted.first().and_then(RedisValue::as_u64).unwrap_or(0) as usize)
+    }
+
+    pub(crate) fn user_key(&self, user_id: &str) -> String {
+        self.prefix_key(&format!("{USER_KEY_PREFIX}{user_id}"))
+    }
+
+    /// records that a session bound to a user was loaded, for evicting the
+    /// least recently used session of the user, and keeps the user set for
+    /// as long as sliding expiration keeps the session
+    pub(crate) async fn touch_user_set(&self, session: &Session, now_millis: i64) -> Result {
+        let Some(user_id) = meta::user_id(session) else {
+            return Ok(());
+        };
+        if let Some(idle) = self.idle_timeout {
+            let (keys, layout) = self.script_keys(session.id(), Some(&user_id));
+            let expires_at = now_millis.saturating_add(millis(idle)).to_string();
+            scripts::extend_user_set()
+                .evalsha_with_reload::<(), _, _>(self.pool.next(), keys, vec![layout, expires_at])
+                .await?;
+        }
+        if !matches!(
+            self.session_limit,
+            Some((_, SessionLimitPolicy::EvictLeastRecentlyUsed))
+        ) {
+            return Ok(());
+        }
+        self.pool
+            .zadd::<(), _, _>(
+                self.user_key(&user_id),
+                Some(SetOptions::XX),
+                None,
+                false,
+                false,
+                (now_millis as f64, session.id()),
+            )
+            .await?;
+        Ok(())
+    }
+}
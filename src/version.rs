@@ -0,0 +1,74 @@
+//! optimistic concurrency control for sessions stored by parallel requests
+
+use async_session::{Result, Session, SessionStore};
+
+use crate::{Error, RedisSessionStore};
+
+/// the first byte of a value that starts with a version header
+const VERSIONED: u8 = 0x0a;
+/// the header byte followed by the big endian version
+const HEADER_LEN: usize = 9;
+
+/// the header written in front of a versioned value in string storage mode
+pub(crate) fn header(version: u64) -> [u8; HEADER_LEN] {
+    let mut header = [VERSIONED; HEADER_LEN];
+    header[1..].copy_from_slice(&version.to_be_bytes());
+    header
+}
+
+/// strips the version header from a stored value, if it has one
+pub(crate) fn strip(bytes: &[u8]) -> &[u8] {
+    match bytes.first() {
+        Some(&VERSIONED) if bytes.len() >= HEADER_LEN => &bytes[HEADER_LEN..],
+        _ => bytes,
+    }
+}
+
+impl RedisSessionStore {
+    /// loads a session, applies `change` to it and stores it, starting over
+    /// if another request stored the session in the meantime, up to
+    /// `attempts` times in total. returns `false` if the session does not
+    /// exist. only useful with
+    /// [versioned sessions](crate::RedisSessionStoreBuilder::versioned_sessions),
+    /// as conflicts are not detected otherwise
+    pub async fn update_session(
+        &self,
+        cookie_value: &str,
+        attempts: u32,
+        mut change: impl FnMut(&mut Session) -> Result + Send,
+    ) -> Result<bool> {
+        let mut attempt = 1;
+        loop {
+            let Some(mut session) = self.load_session(cookie_value.to_string()).await? else {
+                return Ok(false);
+            };
+            change(&mut session)?;
+
+            match self.store_session(session).await {
+                Ok(_) => return Ok(true),
+                Err(error)
+                    if attempt < attempts
+                        && matches!(error.downcast_ref(), Some(Error::VersionConflict(_))) =>
+                {
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stripping_the_version_header() {
+        let mut bytes = header(258).to_vec();
+        assert_eq!(&[VERSIONED, 0, 0, 0, 0, 0, 0, 1, 2], &bytes[..]);
+
+        bytes.extend(br#"{"id":"id"}"#);
+        assert_eq!(br#"{"id":"id"}"#, strip(&bytes));
+        assert_eq!(br#"{"id":"id"}"#, strip(br#"{"id":"id"}"#));
+    }
+}